@@ -1,26 +1,110 @@
 use std::env;
 
+use serde::Deserialize;
+
+
+/// A row of the upstream routing table, packages matching `pattern` (e.g. `@veto/*`) are
+/// fetched from `url` instead of the default registry.
+#[derive(Deserialize, Clone)]
+pub struct UpstreamConfig {
+    pub pattern: String,
+    pub url: String,
+    pub auth: Option<String>,
+}
+
 
 pub struct Config {
     pub self_url: String,
     pub registry_url: String,
+    pub upstreams: Vec<UpstreamConfig>,
+    pub upstream_credentials: Option<String>,
+    pub upstream_credentials_reload: u64,
+    pub policy: Option<String>,
+    pub policy_reload: u64,
+    pub audit_database: Option<String>,
+    pub audit_database_reload: u64,
     pub oidc_url: String,
     pub oidc_client_secret: String,
     pub oidc_client_id: String,
     pub redis_uri: String,
+    pub token_ttl: u64,
+    pub token_secret: String,
+    pub session_refresh: u64,
+    pub token_reauth_window: u64,
+    pub admins: Vec<String>,
+    pub client_ip_header: Option<String>,
+    pub metadata_ttl: u64,
+    pub metadata_max_stale: u64,
+    pub min_age: u64,
+    pub min_age_overrides: Vec<(String, u64)>,
+    pub cache_store: String,
+    pub s3_endpoint: String,
+    pub s3_bucket: String,
+    pub s3_region: String,
+    pub s3_access_key: String,
+    pub s3_secret_key: String,
+    pub cache_max_bytes: u64,
+    pub cache_max_age: u64,
+    pub cache_eviction_interval: u64,
+    pub cache_pinned: Vec<String>,
+    pub local_precedence: String,
+    pub publish_over_upstream: Vec<String>,
+    pub prewarm_concurrency: usize,
+    pub search_upstream: bool,
+    pub offline: bool,
+    pub compression_min_size: usize,
+    pub precompress_min_size: usize,
     pub dev: bool
 }
 
 impl Config {
-    pub fn new() -> Self {
-        return Self {
+    pub fn new() -> Result<Self, String> {
+        let Some(token_secret) = env::var("PROXY_TOKEN_SECRET").ok().filter(|secret| !secret.is_empty()) else {
+            return Err("PROXY_TOKEN_SECRET has to be set, tokens are stored as hashes keyed with it".to_string());
+        };
+
+        return Ok(Self {
             self_url: env::var("PROXY_REGISTRY_HOST").unwrap_or("http://localhost:5000/".to_string()),
             registry_url: env::var("PROXY_REGISTRY_URI").unwrap_or("https://registry.npmjs.org/".to_string()),
+            upstreams: serde_json::from_str(&env::var("PROXY_UPSTREAMS").unwrap_or("[]".to_string())).unwrap(),
+            upstream_credentials: env::var("PROXY_UPSTREAM_CREDENTIALS").ok(),
+            upstream_credentials_reload: env::var("PROXY_UPSTREAM_CREDENTIALS_RELOAD").unwrap_or("30".to_string()).parse().unwrap(),
+            policy: env::var("PROXY_POLICY").ok().filter(|path| !path.is_empty()),
+            policy_reload: env::var("PROXY_POLICY_RELOAD").unwrap_or("30".to_string()).parse().unwrap(),
+            audit_database: env::var("PROXY_AUDIT_DATABASE").ok().filter(|path| !path.is_empty()),
+            audit_database_reload: env::var("PROXY_AUDIT_DATABASE_RELOAD").unwrap_or("300".to_string()).parse().unwrap(),
             oidc_url:  env::var("OIDC_ISSUER_URL").unwrap_or("https://gitlab.git.veto.dev".to_string()),
             oidc_client_secret: env::var("OIDC_CLIENT_ID").unwrap_or("some-id".to_string()),
             oidc_client_id: env::var("OIDC_CLIENT_SECRET").unwrap_or("some-secret".to_string()),
             redis_uri: env::var("REDIS_URI").unwrap_or("redis://localhost:6379".to_string()),
+            token_ttl: env::var("PROXY_TOKEN_TTL").unwrap_or("7776000".to_string()).parse().unwrap(),
+            session_refresh: env::var("PROXY_SESSION_REFRESH").unwrap_or("3600".to_string()).parse().unwrap(),
+            token_secret,
+            token_reauth_window: env::var("PROXY_TOKEN_REAUTH_WINDOW").unwrap_or("600".to_string()).parse().unwrap(),
+            admins: env::var("PROXY_ADMINS").unwrap_or_default().split(',').map(|admin| admin.trim().to_string()).filter(|admin| !admin.is_empty()).collect(),
+            client_ip_header: env::var("PROXY_CLIENT_IP_HEADER").ok().filter(|header| !header.is_empty()),
+            metadata_ttl: env::var("PROXY_METADATA_TTL").unwrap_or("300".to_string()).parse().unwrap(),
+            metadata_max_stale: env::var("PROXY_METADATA_MAX_STALE").unwrap_or("86400".to_string()).parse().unwrap(),
+            min_age: env::var("PROXY_MIN_AGE").unwrap_or("0".to_string()).parse().unwrap(),
+            min_age_overrides: env::var("PROXY_MIN_AGE_OVERRIDES").unwrap_or_default().split(',').filter_map(|entry| entry.split_once('=')).map(|(pattern, seconds)| (pattern.trim().to_string(), seconds.trim().parse().unwrap())).collect(),
+            cache_store: env::var("PROXY_CACHE_STORE").unwrap_or("fs".to_string()),
+            s3_endpoint: env::var("PROXY_S3_ENDPOINT").unwrap_or("http://localhost:9000".to_string()),
+            s3_bucket: env::var("PROXY_S3_BUCKET").unwrap_or("npm-proxy".to_string()),
+            s3_region: env::var("PROXY_S3_REGION").unwrap_or("us-east-1".to_string()),
+            s3_access_key: env::var("PROXY_S3_ACCESS_KEY").unwrap_or_default(),
+            s3_secret_key: env::var("PROXY_S3_SECRET_KEY").unwrap_or_default(),
+            cache_max_bytes: env::var("PROXY_CACHE_MAX_BYTES").unwrap_or("0".to_string()).parse().unwrap(),
+            cache_max_age: env::var("PROXY_CACHE_MAX_AGE").unwrap_or("0".to_string()).parse().unwrap(),
+            cache_eviction_interval: env::var("PROXY_CACHE_EVICTION_INTERVAL").unwrap_or("600".to_string()).parse().unwrap(),
+            local_precedence: env::var("PROXY_LOCAL_PRECEDENCE").unwrap_or("upstream".to_string()),
+            publish_over_upstream: env::var("PROXY_PUBLISH_OVER_UPSTREAM").unwrap_or_default().split(',').map(|pattern| pattern.trim().to_string()).filter(|pattern| !pattern.is_empty()).collect(),
+            prewarm_concurrency: env::var("PROXY_PREWARM_CONCURRENCY").unwrap_or("8".to_string()).parse().unwrap(),
+            search_upstream: env::var("PROXY_SEARCH_UPSTREAM").unwrap_or("false".to_string()).as_str().parse().unwrap(),
+            offline: env::var("PROXY_OFFLINE").unwrap_or("false".to_string()).as_str().parse().unwrap(),
+            compression_min_size: env::var("PROXY_COMPRESSION_MIN_SIZE").unwrap_or("1024".to_string()).parse().unwrap(),
+            precompress_min_size: env::var("PROXY_PRECOMPRESS_MIN_SIZE").unwrap_or("65536".to_string()).parse().unwrap(),
+            cache_pinned: env::var("PROXY_CACHE_PINNED").unwrap_or_default().split(',').map(|package| package.trim().to_string()).filter(|package| !package.is_empty()).collect(),
             dev: env::var("DEV").unwrap_or("false".to_string()).as_str().parse().unwrap()
-        }
+        });
     }
 }
\ No newline at end of file
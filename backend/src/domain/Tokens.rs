@@ -1,6 +1,101 @@
+use std::{collections::BTreeSet, net::IpAddr};
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
 
 pub struct Tokens {
     pub refresh_token: String,
     pub access_token: String,
+    pub identity: Identity,
+}
+
+/// The OIDC tokens of a login, kept to refresh them.
+#[derive(Serialize, Deserialize)]
+pub struct Session {
+    pub subject: String,
+    pub access_token: String,
+    pub refresh_token: String,
+    /// Unix timestamp (seconds) of the login or its last refresh.
+    pub refreshed: i64,
+}
+
+/// Who signed in, from the claims of the ID token.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Identity {
+    pub subject: String,
+    pub username: Option<String>,
+    pub email: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Publish,
+    Admin
+}
+
+impl Scope {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Scope::Read => "read",
+            Scope::Publish => "publish",
+            Scope::Admin => "admin"
+        };
+    }
+}
+
+/// What is stored for every npm token.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TokenRecord {
+    pub owner: Identity,
+    /// The start of the token, only its hash is stored.
+    #[serde(default)]
+    pub prefix: String,
+    /// Given by the user when creating the token through the token API.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Issued by an OIDC login rather than made from another token.
+    #[serde(default)]
+    pub from_login: bool,
+    /// The login the token descends from, it is revoked when the identity provider ends it.
+    #[serde(default)]
+    pub session: Option<String>,
+    pub scopes: BTreeSet<Scope>,
+    /// Networks the token may be used from, anywhere when empty.
+    #[serde(default)]
+    pub cidr_whitelist: Vec<String>,
+    /// Unix timestamps (seconds).
+    pub created: i64,
+    pub last_used: i64,
+    /// Never expires when missing.
+    pub expires: Option<i64>,
 }
 
+impl TokenRecord {
+    /// Tokens from before records were kept only stored `true`, nothing is known about them.
+    pub fn legacy() -> Self {
+        return Self {
+            owner: Identity::default(),
+            prefix: String::new(),
+            name: None,
+            from_login: false,
+            session: None,
+            scopes: BTreeSet::from([Scope::Read, Scope::Publish]),
+            cidr_whitelist: vec![],
+            created: 0,
+            last_used: 0,
+            expires: None
+        };
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        return self.expires.is_some_and(|expires| expires <= now);
+    }
+
+    pub fn allows_address(&self, address: IpAddr) -> bool {
+        return self.cidr_whitelist.is_empty() || self.cidr_whitelist.iter()
+            .filter_map(|network| network.parse::<IpNet>().ok())
+            .any(|network| network.contains(&address));
+    }
+}
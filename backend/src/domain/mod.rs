@@ -1 +1,2 @@
+#[allow(non_snake_case)]
 pub mod Tokens;
\ No newline at end of file
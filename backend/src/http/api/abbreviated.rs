@@ -0,0 +1,156 @@
+use serde_json::{Map, Value};
+
+/// Cache uris below this prefix load the abbreviated packument of the package that follows.
+pub const PREFIX: &str = "-/abbreviated/";
+
+pub const CONTENT_TYPE: &str = "application/vnd.npm.install-v1+json";
+
+/// What the registry keeps of every version in the abbreviated document, everything an
+/// install needs to resolve the tree.
+const VERSION_FIELDS: [&str; 17] = [
+    "name",
+    "version",
+    "deprecated",
+    "dependencies",
+    "optionalDependencies",
+    "devDependencies",
+    "bundleDependencies",
+    "bundledDependencies",
+    "peerDependencies",
+    "peerDependenciesMeta",
+    "acceptDependencies",
+    "bin",
+    "directories",
+    "dist",
+    "engines",
+    "_hasShrinkwrap",
+    "hasInstallScript"
+];
+
+/// Platform fields, only kept when set.
+const PLATFORM_FIELDS: [&str; 3] = ["cpu", "os", "libc"];
+
+const INSTALL_SCRIPTS: [&str; 3] = ["preinstall", "install", "postinstall"];
+
+
+/// Whether a client asked for abbreviated metadata. npm and pnpm list it before `application/json`.
+pub fn is_requested(accept: Option<&str>) -> bool {
+    return accept.is_some_and(|accept| accept.contains(CONTENT_TYPE));
+}
+
+/// Strips a full packument down to the abbreviated ("corgi") form.
+pub fn abbreviate(packument: &Value) -> Value {
+    let mut abbreviated = Map::new();
+
+    for field in ["name", "dist-tags"] {
+        if let Some(value) = packument.get(field) {
+            abbreviated.insert(field.to_string(), value.clone());
+        }
+    }
+
+    if let Some(modified) = packument["time"].get("modified") {
+        abbreviated.insert("modified".to_string(), modified.clone());
+    }
+
+    let versions: Map<String, Value> = packument["versions"].as_object()
+        .map(|versions| versions.iter().map(|(version, manifest)| (version.clone(), abbreviate_version(manifest))).collect())
+        .unwrap_or_default();
+
+    abbreviated.insert("versions".to_string(), Value::Object(versions));
+    return Value::Object(abbreviated);
+}
+
+fn abbreviate_version(manifest: &Value) -> Value {
+    let mut abbreviated = Map::new();
+
+    for field in VERSION_FIELDS.iter().chain(PLATFORM_FIELDS.iter()) {
+        if let Some(value) = manifest.get(field).filter(|value| !value.is_null()) {
+            abbreviated.insert(field.to_string(), value.clone());
+        }
+    }
+
+    // The registry derives the flag at publish, packages published here never had it set.
+    if !abbreviated.contains_key("hasInstallScript") && INSTALL_SCRIPTS.iter().any(|script| manifest["scripts"].get(script).is_some()) {
+        abbreviated.insert("hasInstallScript".to_string(), Value::Bool(true));
+    }
+
+    return Value::Object(abbreviated);
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn keeps_what_installs_need() {
+        let packument = json!({
+            "name": "left-pad",
+            "dist-tags": { "latest": "1.3.0" },
+            "readme": "# left-pad",
+            "maintainers": [{ "name": "someone" }],
+            "time": { "created": "2014-03-14T00:00:00.000Z", "modified": "2018-04-20T00:00:00.000Z", "1.3.0": "2018-04-20T00:00:00.000Z" },
+            "versions": {
+                "1.3.0": {
+                    "name": "left-pad",
+                    "version": "1.3.0",
+                    "description": "String left pad",
+                    "readme": "# left-pad",
+                    "deprecated": "use String.prototype.padStart()",
+                    "dependencies": { "a": "^1.0.0" },
+                    "bin": { "left-pad": "cli.js" },
+                    "engines": { "node": ">=4" },
+                    "os": ["linux"],
+                    "cpu": null,
+                    "_hasShrinkwrap": false,
+                    "scripts": { "test": "tape", "build": "tsc" },
+                    "dist": { "tarball": "https://registry.npmjs.org/left-pad/-/left-pad-1.3.0.tgz", "integrity": "sha512-00" }
+                }
+            }
+        });
+
+        assert_eq!(abbreviate(&packument), json!({
+            "name": "left-pad",
+            "dist-tags": { "latest": "1.3.0" },
+            "modified": "2018-04-20T00:00:00.000Z",
+            "versions": {
+                "1.3.0": {
+                    "name": "left-pad",
+                    "version": "1.3.0",
+                    "deprecated": "use String.prototype.padStart()",
+                    "dependencies": { "a": "^1.0.0" },
+                    "bin": { "left-pad": "cli.js" },
+                    "engines": { "node": ">=4" },
+                    "os": ["linux"],
+                    "_hasShrinkwrap": false,
+                    "dist": { "tarball": "https://registry.npmjs.org/left-pad/-/left-pad-1.3.0.tgz", "integrity": "sha512-00" }
+                }
+            }
+        }));
+    }
+
+    #[test]
+    fn flags_install_scripts() {
+        let packument = json!({
+            "versions": {
+                "1.0.0": { "scripts": { "postinstall": "node setup.js" } },
+                "1.1.0": { "scripts": { "test": "tape" } },
+                "1.2.0": { "hasInstallScript": false, "scripts": { "install": "node-gyp rebuild" } }
+            }
+        });
+
+        let abbreviated = abbreviate(&packument);
+
+        assert_eq!(abbreviated["versions"]["1.0.0"], json!({ "hasInstallScript": true }));
+        assert_eq!(abbreviated["versions"]["1.1.0"], json!({}));
+        assert_eq!(abbreviated["versions"]["1.2.0"], json!({ "hasInstallScript": false }));
+    }
+
+    #[test]
+    fn detects_abbreviated_requests() {
+        assert!(is_requested(Some("application/vnd.npm.install-v1+json; q=1.0, application/json; q=0.8, */*")));
+        assert!(!is_requested(Some("application/json")));
+        assert!(!is_requested(None));
+    }
+}
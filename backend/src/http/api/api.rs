@@ -1,8 +1,8 @@
-use std::{collections::HashMap, sync::Arc};
-use base64::{prelude::BASE64_STANDARD, Engine};
-use tokio::{fs, sync::RwLock};
+use std::{collections::HashMap, io, sync::Arc};
+use serde_json::{json, Value};
+use tokio::sync::RwLock;
 
-use crate::http::api::{inner::{ApiInner, ApiInnerResult}, storage::ApiStorage};
+use crate::http::api::{LOCKFILE_BODY_LIMIT, abbreviated, audit::Endpoint, compression::Encoding, error::Error, inner::{ApiInner, ApiInnerResult}, storage::ApiStorage};
 
 
 pub struct Api {
@@ -20,7 +20,7 @@ impl Clone for Api {
 }
 
 impl Api {
-    async fn load(&mut self, uri: String) -> Result<ApiStorage, ()> {
+    async fn load(&mut self, uri: String) -> Result<ApiStorage, Error> {
 
         let mut created = false;
         let has_key = self.running_requests.read().await.contains_key(&uri);
@@ -32,14 +32,12 @@ impl Api {
         let result = async {
 
             let running = self.running_requests.read().await;
-            let option = running.get(&uri).unwrap();
-            let result = option.call().await;
-            
-            if let Ok(awiated) = result {
-                return Ok(awiated.clone());
+
+            if let Some(option) = running.get(&uri) {
+                return option.call().await;
             }
 
-            return Err(());
+            return Err(Error::Unknown());
         }.await;
 
         if created {
@@ -49,46 +47,84 @@ impl Api {
         return result;
     }
 
+    /// Keys below `-/` are the proxy's own state (tag overlays, published packages, compressed
+    /// variants), not packages to show or delete.
     pub async fn get_cached_packages(&self) -> Vec<String> {
-        let result = fs::read_dir(self.api_inner.cache.clone()).await;
+        let keys = self.api_inner.store.list().await.unwrap_or_default();
+        return keys.into_iter().filter(|key| !key.starts_with("-/")).collect();
+    }
 
-        if result.is_err() {
-            return Vec::new();
+    pub async fn delete_cached_file(&self, package_name: String) {
+        let uri = urlencoding::encode(&package_name).to_string();
+        self.api_inner.index.forget(&uri).await;
+        self.api_inner.search.remove(&package_name).await;
+
+        if let Err(error) = self.api_inner.store.delete(&uri).await {
+            println!("Could not delete {package_name}: {error}");
         }
+    }
 
-        let mut dir =result.unwrap();
+    pub async fn search(&self, text: String, from: usize, size: usize) -> Value {
+        return self.api_inner.search(&text, from, size).await;
+    }
 
-        let mut vec: Vec<String> = Vec::new();
+    /// An audit request as posted by npm, gzip compressed or not.
+    pub async fn audit(&self, endpoint: Endpoint, encoding: Option<&str>, body: Vec<u8>) -> Result<ApiStorage, Error> {
+        let body = match encoding.filter(|encoding| !encoding.eq_ignore_ascii_case("identity")) {
+            Some(name) => Encoding::of(name)
+                .ok_or(Error::Status(415, format!("unsupported content-encoding {name}")))?
+                .decompress(&body, LOCKFILE_BODY_LIMIT)
+                .map_err(|error| match error.kind() {
+                    io::ErrorKind::FileTooLarge => Error::Status(413, format!("the audit request {error}")),
+                    _ => Error::Status(400, format!("could not decompress the audit request: {error}"))
+                })?,
+            None => body
+        };
+
+        return self.api_inner.audit(endpoint, body).await;
+    }
 
-        while let Some(file) = dir.next_entry().await.unwrap() {
-            if file.file_type().await.unwrap().is_dir() {
-                continue;
-            }
+    pub async fn offline_misses(&self) -> Value {
+        return json!({ "offline": self.api_inner.offline.enabled, "misses": self.api_inner.offline.misses().await });
+    }
 
-            if let Ok(result) = BASE64_STANDARD.decode(file.file_name().to_str().unwrap().to_string().strip_suffix(".bin").unwrap().to_string()) {
-                vec.push(String::from_utf8(result).unwrap());
-            }
-        }
+    pub async fn clear_offline_misses(&self) {
+        self.api_inner.offline.clear().await;
+    }
 
-        return vec;
+    pub async fn release_quarantined(&self, package_name: String, version: String) -> Result<(), Error> {
+        return self.api_inner.release_quarantined(&package_name, &version).await;
     }
 
-    pub async fn delete_cached_file(&self, package_name: String) {
-        let mut path = self.api_inner.cache.clone();
-        path.push(BASE64_STANDARD.encode(urlencoding::encode(&package_name).to_string()) + ".bin");
+    pub async fn publish(&self, package_name: String, payload: Value) -> Result<(), Error> {
+        return self.api_inner.publish(&package_name, payload).await;
+    }
+
+    pub async fn get_package_metadata(&mut self, package_name: String) -> Result<ApiStorage, Error> {
+        return self.load( urlencoding::encode(&package_name).to_string()).await;
+    }
+
+    /// The packument as a client asked for it, abbreviated and compressed or not.
+    pub async fn serve_package_metadata(&mut self, package_name: String, abbreviated: bool, encoding: Option<Encoding>) -> Result<ApiStorage, Error> {
+        let mut uri = urlencoding::encode(&package_name).to_string();
+
+        if abbreviated {
+            uri = abbreviated::PREFIX.to_string() + &uri;
+        }
 
-        fs::remove_file(path).await.unwrap();
+        let stored = self.load(uri.clone()).await?;
+        return Ok(self.api_inner.compress(&uri, stored, encoding).await);
     }
 
-    pub async fn get_package_metadata(&mut self, package_name: String) -> Result<ApiStorage, ()> {
-        return Ok(self.load( urlencoding::encode(&package_name).to_string()).await.unwrap().clone());
+    pub async fn get_file(&mut self, package_name: String, file_name: String) -> Result<ApiStorage, Error> {
+        return self.load( urlencoding::encode(&package_name).to_string() +  "/-/" + &urlencoding::encode(&file_name)).await;
     }
 
-    pub async fn get_file(&mut self, package_name: String, file_name: String) -> Result<ApiStorage, ()> {
-        return Ok(self.load( urlencoding::encode(&package_name).to_string() +  "/-/" + &urlencoding::encode(&file_name).to_string()).await.unwrap().clone());
+    pub async fn set_dist_tag(&self, package_name: String, tag: String, version: Option<String>) -> Result<ApiStorage, Error> {
+        return self.api_inner.set_dist_tag(&package_name, &tag, version).await;
     }
 
-    pub async fn get_dist_tags(&mut self, package_name: String) -> Result<ApiStorage, ()> {
-        return Ok(self.load( "-/package/".to_string() + &urlencoding::encode(&package_name).to_string() + "/dist-tags").await.unwrap().clone());
+    pub async fn get_dist_tags(&mut self, package_name: String) -> Result<ApiStorage, Error> {
+        return self.load( "-/package/".to_string() + &urlencoding::encode(&package_name) + "/dist-tags").await;
     }
 }
\ No newline at end of file
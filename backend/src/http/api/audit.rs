@@ -0,0 +1,405 @@
+use std::{collections::{HashMap, HashSet}, path::PathBuf, sync::Arc, time::{Duration, SystemTime}};
+
+use semver::{Version, VersionReq};
+use serde_json::{json, Map, Value};
+use tokio::{fs, sync::{Mutex, RwLock}};
+
+use crate::{config::Config, http::api::{compression::Compression, freshness::FreshnessPolicy, policy::Policy, storage::ApiStorage}};
+
+const SEVERITIES: [&str; 5] = ["info", "low", "moderate", "high", "critical"];
+
+
+/// The two audit requests of npm. It posts the installed versions to the bulk endpoint and
+/// falls back to the quick one, which takes the whole lockfile, when that fails.
+#[derive(Clone, Copy)]
+pub enum Endpoint {
+    Bulk,
+    Quick
+}
+
+impl Endpoint {
+    pub fn path(&self) -> &'static str {
+        return match self {
+            Endpoint::Bulk => "-/npm/v1/security/advisories/bulk",
+            Endpoint::Quick => "-/npm/v1/security/audits/quick"
+        };
+    }
+}
+
+struct Advisory {
+    value: Value,
+    ranges: Vec<VersionReq>,
+}
+
+impl Advisory {
+    fn affects(&self, version: &str) -> bool {
+        let Ok(version) = Version::parse(version.trim_start_matches('v')) else {
+            return false;
+        };
+
+        return self.ranges.iter().any(|range| range.matches(&version));
+    }
+}
+
+/// Answers `npm audit`. With `PROXY_AUDIT_DATABASE` set the advisories come from a json dump
+/// in the format of the bulk endpoint's answer, package names mapped to lists of
+/// `{id, url, title, severity, vulnerable_versions}`, and nothing is asked upstream. The dump
+/// is re-read whenever it changes. Without one audits are forwarded to the default registry
+/// and its answers kept for the metadata TTL, stale ones are served while it is unreachable.
+pub struct Audit {
+    path: Option<PathBuf>,
+    advisories: RwLock<HashMap<String, Vec<Advisory>>>,
+    modified: RwLock<Option<SystemTime>>,
+    /// Upstream answers by endpoint and digest of the request. They are not written to the
+    /// cache store, every lockfile is a new key and eviction only ever removes tarballs.
+    answers: Mutex<HashMap<String, ApiStorage>>,
+    ttl: Duration,
+    max_stale: Duration,
+}
+
+impl Audit {
+    pub fn new(config: &Config) -> Arc<Self> {
+        let element = Arc::new(Self {
+            path: config.audit_database.clone().map(PathBuf::from),
+            advisories: RwLock::new(HashMap::new()),
+            modified: RwLock::new(None),
+            answers: Mutex::new(HashMap::new()),
+            ttl: Duration::from_secs(config.metadata_ttl),
+            max_stale: Duration::from_secs(config.metadata_max_stale)
+        });
+
+        if element.path.is_none() {
+            return element;
+        }
+
+        let element_clone = Arc::clone(&element);
+        let reload_interval = Duration::from_secs(config.audit_database_reload);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(reload_interval);
+            loop {
+                interval.tick().await;
+                element_clone.reload().await;
+            }
+        });
+
+        return element;
+    }
+
+    /// Whether audits are answered from the imported database.
+    pub fn is_local(&self) -> bool {
+        return self.path.is_some();
+    }
+
+    pub fn json(report: &Value) -> ApiStorage {
+        return ApiStorage {
+            headers: HashMap::from([("content-type".to_string(), b"application/json".to_vec())]),
+            body: serde_json::to_vec(report).unwrap(),
+            stored_at: FreshnessPolicy::now(),
+            integrity: None,
+            stream: None
+        };
+    }
+
+    /// Answers a bulk request, `{"name": ["1.0.0", ...]}`, with the advisories affecting any
+    /// of the versions.
+    pub async fn bulk(&self, request: &Value) -> Result<Value, String> {
+        let Some(request) = request.as_object() else {
+            return Err("expected an object of package names and their versions".to_string());
+        };
+
+        let installed: HashMap<String, HashSet<String>> = request.iter()
+            .map(|(name, versions)| {
+                let versions = versions.as_array().map(|versions| versions.iter().filter_map(|version| version.as_str()).map(|version| version.to_string()).collect()).unwrap_or_default();
+                (name.clone(), versions)
+            })
+            .collect();
+
+        let mut report = Map::new();
+
+        for (name, advisories) in self.affecting(&installed).await {
+            report.insert(name, Value::Array(advisories.into_iter().map(|(advisory, _)| advisory).collect()));
+        }
+
+        return Ok(Value::Object(report));
+    }
+
+    /// Answers a quick audit with the legacy report npm converts back to the bulk form. The
+    /// request carries the lockfile, `dependencies` nested the v1 way or flat `packages`.
+    pub async fn quick(&self, request: &Value) -> Value {
+        let mut installed: HashMap<String, HashSet<String>> = HashMap::new();
+        let mut dev = 0;
+        let mut optional = 0;
+        let mut total = 0;
+
+        let mut count = |name: String, manifest: &Value, installed: &mut HashMap<String, HashSet<String>>| {
+            let Some(version) = manifest["version"].as_str() else {
+                return;
+            };
+
+            total += 1;
+            dev += manifest["dev"].as_bool().unwrap_or(false) as usize;
+            optional += manifest["optional"].as_bool().unwrap_or(false) as usize;
+            installed.entry(name).or_default().insert(version.to_string());
+        };
+
+        if let Some(packages) = request["packages"].as_object() {
+            for (path, manifest) in packages.iter().filter(|(path, _)| !path.is_empty()) {
+                let name = manifest["name"].as_str().or(path.rsplit("node_modules/").next()).unwrap_or_default();
+                count(name.to_string(), manifest, &mut installed);
+            }
+        } else {
+            let mut pending: Vec<&Map<String, Value>> = request["dependencies"].as_object().into_iter().collect();
+
+            while let Some(dependencies) = pending.pop() {
+                for (name, manifest) in dependencies {
+                    count(name.clone(), manifest, &mut installed);
+                    pending.extend(manifest["dependencies"].as_object());
+                }
+            }
+        }
+
+        let mut advisories = Map::new();
+        let mut vulnerabilities: Map<String, Value> = SEVERITIES.iter().map(|severity| (severity.to_string(), json!(0))).collect();
+
+        for (name, affecting) in self.affecting(&installed).await {
+            for (mut advisory, versions) in affecting {
+                let id = match &advisory["id"] {
+                    Value::String(id) => id.clone(),
+                    id => id.to_string()
+                };
+                let severity = advisory["severity"].as_str().unwrap_or("info").to_string();
+
+                if let Some(counted) = vulnerabilities.get_mut(&severity) {
+                    *counted = json!(counted.as_u64().unwrap_or(0) + versions.len() as u64);
+                }
+
+                advisory["module_name"] = json!(name);
+                advisory["findings"] = versions.into_iter().map(|version| json!({ "version": version, "paths": [name] })).collect();
+                advisories.insert(id, advisory);
+            }
+        }
+
+        return json!({
+            "actions": [],
+            "advisories": advisories,
+            "muted": [],
+            "metadata": {
+                "vulnerabilities": vulnerabilities,
+                "dependencies": total - dev,
+                "devDependencies": dev,
+                "optionalDependencies": optional,
+                "totalDependencies": total
+            }
+        });
+    }
+
+    /// The advisories affecting the installed packages, with the affected versions.
+    async fn affecting(&self, installed: &HashMap<String, HashSet<String>>) -> Vec<(String, Vec<(Value, Vec<String>)>)> {
+        let database = self.advisories.read().await;
+        let mut affecting = Vec::new();
+
+        for (name, versions) in installed {
+            let Some(advisories) = database.get(name) else {
+                continue;
+            };
+
+            let found: Vec<(Value, Vec<String>)> = advisories.iter()
+                .map(|advisory| (advisory, versions.iter().filter(|version| advisory.affects(version)).cloned().collect::<Vec<String>>()))
+                .filter(|(_, affected)| !affected.is_empty())
+                .map(|(advisory, affected)| (advisory.value.clone(), affected))
+                .collect();
+
+            if !found.is_empty() {
+                affecting.push((name.clone(), found));
+            }
+        }
+
+        return affecting;
+    }
+
+    pub fn key(endpoint: Endpoint, request: &[u8]) -> String {
+        return endpoint.path().to_string() + "/" + &String::from_utf8(Compression::digest(request)).unwrap();
+    }
+
+    /// A kept upstream answer, and whether it is still fresh.
+    pub async fn answer(&self, key: &str) -> Option<(ApiStorage, bool)> {
+        return self.answers.lock().await.get(key).map(|answer| (answer.clone(), answer.age() < self.ttl));
+    }
+
+    pub async fn remember(&self, key: String, answer: ApiStorage) {
+        let mut answers = self.answers.lock().await;
+        answers.retain(|_, answer| answer.age() < self.ttl + self.max_stale);
+        answers.insert(key, answer);
+    }
+
+    async fn reload(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        let modified = match fs::metadata(path).await.and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(error) => {
+                println!("Could not read advisory database {}: {error}", path.display());
+                return;
+            }
+        };
+
+        if *self.modified.read().await == Some(modified) {
+            return;
+        }
+
+        let content = match fs::read(path).await {
+            Ok(content) => content,
+            Err(error) => {
+                println!("Could not read advisory database {}: {error}", path.display());
+                return;
+            }
+        };
+
+        // A broken dump keeps the previous advisories and is read again on the next reload,
+        // one that is still being written is picked up once it is complete.
+        let dump: HashMap<String, Vec<Value>> = match serde_json::from_slice(&content) {
+            Ok(dump) => dump,
+            Err(error) => {
+                println!("Ignoring advisory database {}, it does not parse: {error}", path.display());
+                return;
+            }
+        };
+
+        let mut advisories = HashMap::new();
+        let mut count = 0;
+        let mut skipped = 0;
+
+        for (name, entries) in dump {
+            let parsed: Vec<Advisory> = entries.into_iter()
+                .filter_map(|value| {
+                    let ranges = value["vulnerable_versions"].as_str().and_then(Policy::parse_range);
+
+                    if ranges.is_none() {
+                        skipped += 1;
+                    }
+
+                    return ranges.map(|ranges| Advisory { value, ranges });
+                })
+                .collect();
+
+            if !parsed.is_empty() {
+                count += parsed.len();
+                advisories.insert(name, parsed);
+            }
+        }
+
+        if skipped > 0 {
+            println!("Skipped {skipped} advisories without a readable vulnerable_versions range");
+        }
+
+        println!("Loaded {count} advisories for {} packages", advisories.len());
+        *self.advisories.write().await = advisories;
+        *self.modified.write().await = Some(modified);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    async fn audit(dump: Value) -> Audit {
+        let path = env::temp_dir().join(format!("npm-proxy-advisories-{}.json", rand::random::<u64>()));
+        fs::write(&path, serde_json::to_vec(&dump).unwrap()).await.unwrap();
+
+        let audit = Audit {
+            path: Some(path.clone()),
+            advisories: RwLock::new(HashMap::new()),
+            modified: RwLock::new(None),
+            answers: Mutex::new(HashMap::new()),
+            ttl: Duration::from_secs(60),
+            max_stale: Duration::from_secs(60)
+        };
+
+        audit.reload().await;
+        fs::remove_file(path).await.unwrap();
+        return audit;
+    }
+
+    fn database() -> Value {
+        return json!({
+            "minimist": [
+                { "id": 1179, "title": "Prototype Pollution", "severity": "moderate", "url": "https://github.com/advisories/1", "vulnerable_versions": "<0.2.1" },
+                { "id": "GHSA-xvch-5gv4-984h", "title": "Prototype Pollution", "severity": "critical", "url": "https://github.com/advisories/2", "vulnerable_versions": ">=1.0.0 <1.2.6" }
+            ],
+            "lodash": [
+                { "id": 1523, "title": "Prototype Pollution", "severity": "low", "url": "https://github.com/advisories/3", "vulnerable_versions": "<4.17.19" },
+                { "id": 1524, "title": "Broken", "severity": "high", "url": "https://github.com/advisories/4", "vulnerable_versions": "not a range" }
+            ]
+        });
+    }
+
+    fn ids(advisories: &Value) -> Vec<String> {
+        return advisories.as_array().unwrap().iter().map(|advisory| advisory["id"].to_string()).collect();
+    }
+
+    #[tokio::test]
+    async fn answers_bulk_requests() {
+        let audit = audit(database()).await;
+        let report = audit.bulk(&json!({
+            "minimist": ["0.0.8", "1.2.5"],
+            "lodash": ["4.17.21"],
+            "left-pad": ["1.3.0"]
+        })).await.unwrap();
+
+        assert_eq!(report.as_object().unwrap().len(), 1);
+        assert_eq!(ids(&report["minimist"]), vec!["1179", "\"GHSA-xvch-5gv4-984h\""]);
+        assert_eq!(ids(&audit.bulk(&json!({ "lodash": ["4.17.15", "v4.17.4"] })).await.unwrap()["lodash"]), vec!["1523"]);
+        assert!(audit.bulk(&json!(["minimist"])).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn answers_quick_audits_of_nested_lockfiles() {
+        let audit = audit(database()).await;
+        let report = audit.quick(&json!({
+            "name": "app",
+            "dependencies": {
+                "minimist": { "version": "1.2.5", "dev": true },
+                "mkdirp": {
+                    "version": "0.5.1",
+                    "optional": true,
+                    "dependencies": { "minimist": { "version": "0.0.8", "optional": true } }
+                }
+            }
+        })).await;
+
+        assert_eq!(report["advisories"]["1179"]["module_name"], "minimist");
+        assert_eq!(report["advisories"]["1179"]["findings"], json!([{ "version": "0.0.8", "paths": ["minimist"] }]));
+        assert_eq!(report["advisories"]["GHSA-xvch-5gv4-984h"]["findings"][0]["version"], "1.2.5");
+        assert_eq!(report["metadata"], json!({
+            "vulnerabilities": { "info": 0, "low": 0, "moderate": 1, "high": 0, "critical": 1 },
+            "dependencies": 2,
+            "devDependencies": 1,
+            "optionalDependencies": 2,
+            "totalDependencies": 3
+        }));
+    }
+
+    #[tokio::test]
+    async fn answers_quick_audits_of_flat_lockfiles() {
+        let audit = audit(database()).await;
+        let report = audit.quick(&json!({
+            "lockfileVersion": 3,
+            "packages": {
+                "": { "name": "app", "version": "1.0.0" },
+                "node_modules/lodash": { "version": "4.17.15" },
+                "node_modules/a/node_modules/lodash": { "version": "4.17.10" },
+                "node_modules/alias": { "name": "minimist", "version": "1.2.0", "dev": true }
+            }
+        })).await;
+
+        assert_eq!(report["advisories"]["1523"]["findings"].as_array().unwrap().len(), 2);
+        assert_eq!(report["advisories"]["GHSA-xvch-5gv4-984h"]["module_name"], "minimist");
+        assert_eq!(report["metadata"]["vulnerabilities"], json!({ "info": 0, "low": 2, "moderate": 0, "high": 0, "critical": 1 }));
+        assert_eq!(report["metadata"]["totalDependencies"], 3);
+        assert_eq!(report["metadata"]["devDependencies"], 1);
+    }
+}
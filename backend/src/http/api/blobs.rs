@@ -0,0 +1,131 @@
+use std::{collections::HashMap, io, path::Path, sync::Arc};
+
+use futures::StreamExt;
+use tokio::sync::Mutex;
+
+use crate::http::api::{download::ApiStream, integrity::{Hasher, Integrity}, store::{BlobStat, CacheStore}};
+
+
+/// Tarballs stored by their digest as `<algorithm>/<hex>`, so identical content is kept once.
+#[derive(Clone)]
+pub struct BlobStore {
+    store: Arc<dyn CacheStore>,
+    /// Blobs hashed since they were last rewritten, with the stat they had then.
+    verified: Arc<Mutex<HashMap<String, BlobStat>>>,
+}
+
+impl BlobStore {
+    pub fn new(store: Arc<dyn CacheStore>) -> Self {
+        return Self {
+            store,
+            verified: Arc::new(Mutex::new(HashMap::new()))
+        };
+    }
+
+    pub fn name(integrity: &Integrity) -> String {
+        return integrity.algorithm.name().to_string() + "/" + &integrity.hex();
+    }
+
+    pub fn stream(&self, integrity: &Integrity, length: u64) -> ApiStream {
+        return ApiStream::Blob(self.store.clone(), BlobStore::name(integrity), length);
+    }
+
+    /// Moves a fully written and checked file into the store.
+    pub async fn commit(&self, file: &Path, integrity: &Integrity) -> Result<(), io::Error> {
+        let name = BlobStore::name(integrity);
+        self.store.put_blob(&name, file).await?;
+
+        if let Ok(Some(stat)) = self.store.stat_blob(&name).await {
+            self.verified.lock().await.insert(name, stat);
+        }
+
+        return Ok(());
+    }
+
+    /// Returns the blob's length if its content still matches `integrity`. Blobs that do not
+    /// are removed so they are downloaded again. A blob is hashed on its first read and again
+    /// whenever its stat changes, never served unchecked.
+    pub async fn verify(&self, integrity: &Integrity) -> Option<u64> {
+        let name = BlobStore::name(integrity);
+        let stat = self.store.stat_blob(&name).await.ok()??;
+
+        if self.verified.lock().await.get(&name) == Some(&stat) {
+            return Some(stat.length);
+        }
+
+        if let Ok(actual) = self.hash(&name, integrity).await && actual == *integrity {
+            let length = stat.length;
+            self.verified.lock().await.insert(name, stat);
+            return Some(length);
+        }
+
+        println!("Removing corrupted blob {name}");
+        self.remove(integrity).await;
+        return None;
+    }
+
+    pub async fn remove(&self, integrity: &Integrity) {
+        let name = BlobStore::name(integrity);
+        self.verified.lock().await.remove(&name);
+        let _ = self.store.delete_blob(&name).await;
+    }
+
+    async fn hash(&self, name: &str, integrity: &Integrity) -> Result<Integrity, io::Error> {
+        let mut stream = self.store.open_blob(name, 0).await?;
+        let mut hasher = Hasher::new(integrity.algorithm);
+
+        while let Some(chunk) = stream.next().await {
+            hasher.update(&chunk?);
+        }
+
+        return Ok(hasher.finish());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use tokio::fs;
+
+    use crate::http::api::{integrity::Algorithm, store::fs::FsStore};
+
+    use super::*;
+
+    async fn stored(root: &Path, content: &[u8]) -> (Arc<dyn CacheStore>, Integrity) {
+        let mut hasher = Hasher::new(Algorithm::Sha512);
+        hasher.update(content);
+        let integrity = hasher.finish();
+
+        let store: Arc<dyn CacheStore> = Arc::new(FsStore::new(root));
+        let file = root.join("tarball.tmp-test");
+        fs::create_dir_all(root).await.unwrap();
+        fs::write(&file, content).await.unwrap();
+        BlobStore::new(store.clone()).commit(&file, &integrity).await.unwrap();
+
+        return (store, integrity);
+    }
+
+    #[tokio::test]
+    async fn serves_intact_blobs_after_a_restart() {
+        let root = env::temp_dir().join(format!("npm-proxy-blobs-{}", rand::random::<u64>()));
+        let (store, integrity) = stored(&root, b"tarball").await;
+
+        assert_eq!(BlobStore::new(store).verify(&integrity).await, Some(7));
+        fs::remove_dir_all(root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn never_serves_damaged_blobs_after_a_restart() {
+        let root = env::temp_dir().join(format!("npm-proxy-blobs-{}", rand::random::<u64>()));
+        let (store, integrity) = stored(&root, b"tarball").await;
+        let name = BlobStore::name(&integrity);
+
+        // Same length, so only the hash tells.
+        fs::write(root.join("blobs").join(&name), b"tarbalL").await.unwrap();
+
+        assert_eq!(BlobStore::new(store.clone()).verify(&integrity).await, None);
+        assert_eq!(store.stat_blob(&name).await.unwrap(), None);
+        fs::remove_dir_all(root).await.unwrap();
+    }
+}
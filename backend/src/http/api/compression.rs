@@ -0,0 +1,144 @@
+use std::io::{self, Read, Write};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression as GzipLevel};
+use sha2::{Digest, Sha256};
+
+use crate::{config::Config, http::api::storage::ApiStorage};
+
+/// Cache uris below this prefix hold compressed variants, `-/compressed/br/<uri>`.
+const PREFIX: &str = "-/compressed/";
+
+/// Header of a stored variant naming the digest of the body it was compressed from.
+pub const SOURCE_HEADER: &str = "source-digest";
+
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Encoding {
+    Brotli,
+    Gzip
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip"
+        };
+    }
+
+    /// The encoding of a `content-encoding` header.
+    pub fn of(name: &str) -> Option<Self> {
+        return [Encoding::Brotli, Encoding::Gzip].into_iter().find(|encoding| name.trim().eq_ignore_ascii_case(encoding.name()));
+    }
+
+    /// Picks brotli over gzip from an `accept-encoding` header, encodings with `q=0` are refused.
+    pub fn negotiate(accept: Option<&str>) -> Option<Self> {
+        let mut accepted: Vec<&str> = Vec::new();
+
+        for part in accept.unwrap_or_default().split(',') {
+            let mut settings = part.split(';').map(|setting| setting.trim());
+            let name = settings.next().unwrap_or_default();
+            let refused = settings.any(|setting| setting.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()).is_some_and(|q| q <= 0.0));
+
+            if !refused {
+                accepted.push(name);
+            }
+        }
+
+        return [Encoding::Brotli, Encoding::Gzip].into_iter()
+            .find(|encoding| accepted.iter().any(|name| name.eq_ignore_ascii_case(encoding.name()) || *name == "*"));
+    }
+
+    /// `best` takes much longer and is meant for variants that are stored and served many times.
+    pub fn compress(&self, body: &[u8], best: bool) -> Vec<u8> {
+        return match self {
+            Encoding::Brotli => {
+                let mut compressed = Vec::new();
+                let params = brotli::enc::BrotliEncoderParams { quality: if best { 11 } else { 4 }, ..Default::default() };
+                brotli::BrotliCompress(&mut &body[..], &mut compressed, &params).unwrap();
+                compressed
+            },
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), if best { GzipLevel::best() } else { GzipLevel::fast() });
+                encoder.write_all(body).unwrap();
+                encoder.finish().unwrap()
+            }
+        };
+    }
+
+    /// Request bodies, npm compresses the ones it posts to audit. Reading stops past `limit`
+    /// bytes with `FileTooLarge`, a small body can expand to gigabytes.
+    pub fn decompress(&self, body: &[u8], limit: usize) -> Result<Vec<u8>, io::Error> {
+        let decoder: Box<dyn Read + '_> = match self {
+            Encoding::Brotli => Box::new(brotli::Decompressor::new(body, 4096)),
+            Encoding::Gzip => Box::new(GzDecoder::new(body))
+        };
+
+        let mut decompressed = Vec::new();
+        decoder.take(limit as u64 + 1).read_to_end(&mut decompressed)?;
+
+        if decompressed.len() > limit {
+            return Err(io::Error::new(io::ErrorKind::FileTooLarge, format!("decompresses to more than {limit} bytes")));
+        }
+
+        return Ok(decompressed);
+    }
+
+    pub fn key(&self, uri: &str) -> String {
+        return PREFIX.to_string() + self.name() + "/" + uri;
+    }
+}
+
+#[derive(Clone)]
+pub struct Compression {
+    /// Smaller bodies are sent as they are.
+    pub min_size: usize,
+    /// Bodies from this size on are compressed once at the best level and the variant is cached.
+    pub precompress_min_size: usize,
+}
+
+impl Compression {
+    pub fn new(config: &Config) -> Self {
+        return Self {
+            min_size: config.compression_min_size,
+            precompress_min_size: config.precompress_min_size
+        };
+    }
+
+    /// Only json documents are compressed, tarballs are gzip already.
+    pub fn applies_to(&self, stored: &ApiStorage) -> bool {
+        let json = stored.header("content-type").is_some_and(|content_type| String::from_utf8_lossy(content_type).contains("json"));
+        return stored.stream.is_none() && json && stored.header("content-encoding").is_none() && stored.body.len() >= self.min_size;
+    }
+
+    pub fn digest(body: &[u8]) -> Vec<u8> {
+        return hex::encode(Sha256::digest(body)).into_bytes();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+
+    #[test]
+    fn decompresses_within_the_limit() {
+        let body = b"{\"lodash\":[\"4.17.20\"]}".repeat(100);
+
+        for encoding in [Encoding::Brotli, Encoding::Gzip] {
+            assert_eq!(encoding.decompress(&encoding.compress(&body, false), body.len()).unwrap(), body);
+        }
+    }
+
+    #[test]
+    fn stops_past_the_limit() {
+        let body = vec![0u8; 1024 * 1024];
+
+        for encoding in [Encoding::Brotli, Encoding::Gzip] {
+            let compressed = encoding.compress(&body, true);
+            assert!(compressed.len() < 4096);
+            assert_eq!(encoding.decompress(&compressed, body.len() - 1).unwrap_err().kind(), io::ErrorKind::FileTooLarge);
+        }
+    }
+}
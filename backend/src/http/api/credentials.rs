@@ -0,0 +1,273 @@
+use std::{collections::HashMap, env, path::PathBuf, sync::Arc, time::{Duration, SystemTime}};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use reqwest::Url;
+use tokio::{fs, sync::RwLock};
+
+use crate::config::Config;
+
+
+#[derive(Clone)]
+pub enum Credential {
+    /// `_authToken`, sent as a bearer token.
+    Bearer(String),
+    /// `_auth` or `username` with `_password`, already encoded as `user:password` in base64.
+    Basic(String),
+}
+
+impl Credential {
+    pub fn header(&self) -> String {
+        return match self {
+            Credential::Bearer(token) => "Bearer ".to_string() + token,
+            Credential::Basic(encoded) => "Basic ".to_string() + encoded
+        };
+    }
+}
+
+// Never print the secret itself.
+impl std::fmt::Debug for Credential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            Credential::Bearer(_) => write!(f, "Bearer(<redacted>)"),
+            Credential::Basic(_) => write!(f, "Basic(<redacted>)")
+        };
+    }
+}
+
+/// Upstream credentials read from an `.npmrc` style file, e.g. a mounted secret:
+///
+/// ```text
+/// //npm.pkg.github.com/:_authToken=${GITHUB_TOKEN}
+/// //gitlab.example.com/api/v4/projects/1/packages/npm/:_auth=dXNlcjpwYXNz
+/// //nexus.example.com/repository/npm/:username=user
+/// //nexus.example.com/repository/npm/:_password=cGFzcw==
+/// ```
+///
+/// The file is re-read whenever it changes, rotating a token needs no restart.
+pub struct Credentials {
+    path: Option<PathBuf>,
+    /// Credentials by nerf-darted url (`//host/path/`).
+    entries: RwLock<HashMap<String, Credential>>,
+    modified: RwLock<Option<SystemTime>>,
+}
+
+impl Credentials {
+    pub fn new(config: &Config) -> Arc<Self> {
+        let element = Arc::new(Self {
+            path: config.upstream_credentials.clone().map(PathBuf::from),
+            entries: RwLock::new(HashMap::new()),
+            modified: RwLock::new(None)
+        });
+
+        if element.path.is_none() {
+            return element;
+        }
+
+        let element_clone = Arc::clone(&element);
+        let reload_interval = Duration::from_secs(config.upstream_credentials_reload);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(reload_interval);
+            loop {
+                interval.tick().await;
+                element_clone.reload().await;
+            }
+        });
+
+        return element;
+    }
+
+    /// The `authorization` header for requests to `upstream`, from the longest matching entry.
+    pub async fn header_for(&self, upstream: &str) -> Option<String> {
+        let nerfed = Credentials::nerf_dart(upstream)?;
+
+        return self.entries.read().await.iter()
+            .filter(|(prefix, _)| nerfed.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, credential)| credential.header());
+    }
+
+    async fn reload(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        let modified = match fs::metadata(path).await.and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(error) => {
+                println!("Could not read upstream credentials {}: {error}", path.display());
+                return;
+            }
+        };
+
+        if *self.modified.read().await == Some(modified) {
+            return;
+        }
+
+        let content = match fs::read_to_string(path).await {
+            Ok(content) => content,
+            Err(error) => {
+                println!("Could not read upstream credentials {}: {error}", path.display());
+                return;
+            }
+        };
+
+        let entries = Credentials::parse(&content);
+        println!("Loaded upstream credentials for {} registries", entries.len());
+
+        *self.entries.write().await = entries;
+        *self.modified.write().await = Some(modified);
+    }
+
+    fn parse(content: &str) -> HashMap<String, Credential> {
+        let mut settings: HashMap<String, HashMap<String, String>> = HashMap::new();
+
+        for line in content.lines() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+
+            let Some((prefix, setting)) = key.trim().rsplit_once(":") else {
+                continue;
+            };
+
+            if !prefix.starts_with("//") {
+                continue;
+            }
+
+            let value = Credentials::expand(value.trim().trim_matches('"'));
+            settings.entry(prefix.to_string()).or_default().insert(setting.to_string(), value);
+        }
+
+        let mut entries: HashMap<String, Credential> = HashMap::new();
+
+        for (prefix, values) in settings {
+            let credential = if let Some(token) = values.get("_authToken") {
+                Credential::Bearer(token.clone())
+            } else if let Some(auth) = values.get("_auth") {
+                Credential::Basic(auth.clone())
+            } else if let Some(username) = values.get("username") && let Some(password) = values.get("_password") {
+                let Some(password) = BASE64_STANDARD.decode(password).ok().and_then(|password| String::from_utf8(password).ok()) else {
+                    println!("Ignoring credentials for {prefix}, _password is not base64");
+                    continue;
+                };
+
+                Credential::Basic(BASE64_STANDARD.encode(username.clone() + ":" + &password))
+            } else {
+                continue;
+            };
+
+            let prefix = if prefix.ends_with('/') { prefix } else { prefix + "/" };
+            entries.insert(prefix, credential);
+        }
+
+        return entries;
+    }
+
+    /// Replaces `${NAME}` with the environment variable, like npm does.
+    fn expand(value: &str) -> String {
+        let mut result = String::new();
+        let mut rest = value;
+
+        while let Some(start) = rest.find("${") {
+            let Some(end) = rest[start..].find('}') else {
+                break;
+            };
+
+            result += &rest[..start];
+            result += &env::var(&rest[start + 2..start + end]).unwrap_or_default();
+            rest = &rest[start + end + 1..];
+        }
+
+        return result + rest;
+    }
+
+    /// `https://host:port/path` as `//host:port/path/`, the form `.npmrc` keys credentials by.
+    fn nerf_dart(url: &str) -> Option<String> {
+        let url = Url::parse(url).ok()?;
+        let mut host = url.host_str()?.to_string();
+
+        if let Some(port) = url.port() {
+            host += &format!(":{port}");
+        }
+
+        let path = url.path().trim_end_matches('/').to_string() + "/";
+        return Some("//".to_string() + &host + &path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials(content: &str) -> Credentials {
+        return Credentials {
+            path: None,
+            entries: RwLock::new(Credentials::parse(content)),
+            modified: RwLock::new(None)
+        };
+    }
+
+    #[test]
+    fn expands_environment_variables() {
+        // Only this test touches the variable, nothing reads it concurrently.
+        unsafe { env::set_var("CREDENTIALS_TEST_TOKEN", "s3cret") };
+
+        assert_eq!(Credentials::expand("${CREDENTIALS_TEST_TOKEN}"), "s3cret");
+        assert_eq!(Credentials::expand("a-${CREDENTIALS_TEST_TOKEN}-b"), "a-s3cret-b");
+        assert_eq!(Credentials::expand("${CREDENTIALS_TEST_MISSING}"), "");
+        assert_eq!(Credentials::expand("plain"), "plain");
+        assert_eq!(Credentials::expand("${UNTERMINATED"), "${UNTERMINATED");
+    }
+
+    #[test]
+    fn parses_npmrc_files() {
+        let entries = Credentials::parse(r#"
+            # comment
+            ; comment
+            registry=https://registry.npmjs.org/
+            //npm.pkg.github.com/:_authToken="token"
+            //gitlab.example.com/api/v4/packages/npm/:_auth=dXNlcjpwYXNz
+            //nexus.example.com/repository/npm/:username=user
+            //nexus.example.com/repository/npm/:_password=cGFzcw==
+            //broken.example.com/:username=user
+            //broken.example.com/:_password=not base64
+            //lonely.example.com/:username=user
+        "#);
+
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries["//npm.pkg.github.com/"].header(), "Bearer token");
+        assert_eq!(entries["//gitlab.example.com/api/v4/packages/npm/"].header(), "Basic dXNlcjpwYXNz");
+        assert_eq!(entries["//nexus.example.com/repository/npm/"].header(), "Basic ".to_string() + &BASE64_STANDARD.encode("user:pass"));
+    }
+
+    #[test]
+    fn prefers_tokens_over_basic_auth() {
+        let entries = Credentials::parse("//registry.example.com:_auth=dXNlcjpwYXNz\n//registry.example.com:_authToken=token");
+
+        assert_eq!(entries["//registry.example.com/"].header(), "Bearer token");
+    }
+
+    #[test]
+    fn nerf_darts_urls() {
+        assert_eq!(Credentials::nerf_dart("https://registry.example.com").unwrap(), "//registry.example.com/");
+        assert_eq!(Credentials::nerf_dart("https://registry.example.com:8443/npm/").unwrap(), "//registry.example.com:8443/npm/");
+        assert_eq!(Credentials::nerf_dart("not a url"), None);
+    }
+
+    #[tokio::test]
+    async fn picks_the_longest_matching_entry() {
+        let credentials = credentials("//example.com/:_authToken=host\n//example.com/npm/private/:_authToken=private\n//example.com/npm/:_authToken=npm");
+
+        assert_eq!(credentials.header_for("https://example.com/npm/private").await.unwrap(), "Bearer private");
+        assert_eq!(credentials.header_for("https://example.com/npm/public/").await.unwrap(), "Bearer npm");
+        assert_eq!(credentials.header_for("https://example.com/other").await.unwrap(), "Bearer host");
+        assert_eq!(credentials.header_for("https://example.com:8443/npm").await, None);
+        assert_eq!(credentials.header_for("https://other.example.com/npm").await, None);
+    }
+}
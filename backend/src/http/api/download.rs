@@ -0,0 +1,257 @@
+use std::{collections::HashMap, io, path::PathBuf, sync::{Arc, Mutex}};
+
+use bytes::Bytes;
+use futures::{StreamExt, TryStreamExt};
+use tokio::{fs::File, io::{AsyncReadExt, AsyncWriteExt}, sync::watch};
+
+use crate::http::api::{blobs::BlobStore, freshness::FreshnessPolicy, integrity::{Algorithm, Hasher, Integrity}, storage::ApiStorage, store::{BlobStream, CacheStore}};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+
+#[derive(Clone, Copy, Debug)]
+pub enum DownloadState {
+    /// Bytes of the partial file readers may hand out so far.
+    Running(u64),
+    Done(u64),
+    Failed
+}
+
+/// A tarball that is being streamed from upstream into the cache. Every client asking for
+/// it in the meantime reads the partial file as it grows instead of waiting for the end.
+pub struct Download {
+    pub partial: PathBuf,
+    /// The stored blob, only known once its digest is.
+    pub complete: Mutex<Option<ApiStream>>,
+    pub length: Option<u64>,
+    pub headers: HashMap<String, Vec<u8>>,
+    /// Digest announced by the packument, the download is rejected when the content does not match.
+    pub expected: Option<Integrity>,
+    progress: watch::Receiver<DownloadState>,
+}
+
+#[derive(Clone)]
+pub enum ApiStream {
+    /// A blob in the cache store, with its length.
+    Blob(Arc<dyn CacheStore>, String, u64),
+    Download(Arc<Download>),
+}
+
+impl Download {
+    pub fn new(partial: PathBuf, length: Option<u64>, headers: HashMap<String, Vec<u8>>, expected: Option<Integrity>) -> (Arc<Self>, watch::Sender<DownloadState>) {
+        let (sender, receiver) = watch::channel(DownloadState::Running(0));
+
+        return (Arc::new(Self {
+            partial,
+            complete: Mutex::new(None),
+            length,
+            headers,
+            expected,
+            progress: receiver
+        }), sender);
+    }
+
+    pub fn storage(self: &Arc<Self>) -> ApiStorage {
+        return ApiStorage {
+            headers: self.headers.clone(),
+            body: vec![],
+            stored_at: FreshnessPolicy::now(),
+            integrity: self.expected.clone(),
+            stream: Some(ApiStream::Download(self.clone()))
+        };
+    }
+
+    /// Copies the upstream body into the partial file and moves it into the blob store once
+    /// complete. Content that does not match the expected digest never reaches the store.
+    pub async fn run(&self, mut file: File, mut response: reqwest::Response, progress: &watch::Sender<DownloadState>, blobs: &BlobStore) -> Result<(u64, Integrity), io::Error> {
+        let mut written: u64 = 0;
+        let mut released: u64 = 0;
+        let mut hasher = Hasher::new(self.expected.as_ref().map(|expected| expected.algorithm).unwrap_or(Algorithm::Sha512));
+
+        while let Some(chunk) = response.chunk().await.map_err(io::Error::other)? {
+            file.write_all(&chunk).await?;
+            file.flush().await?;
+            hasher.update(&chunk);
+
+            // Readers lag one chunk behind, so the end of a tarball that fails verification is never handed out.
+            progress.send_replace(DownloadState::Running(released));
+            released = written;
+            written += chunk.len() as u64;
+        }
+
+        file.sync_all().await?;
+
+        let actual = hasher.finish();
+
+        if let Some(expected) = &self.expected && *expected != actual {
+            return Err(io::Error::other(format!("integrity mismatch, expected {expected} but got {actual}")));
+        }
+
+        blobs.commit(&self.partial, &actual).await?;
+        let _ = self.complete.lock().unwrap().insert(blobs.stream(&actual, written));
+
+        return Ok((written, actual));
+    }
+}
+
+impl ApiStream {
+    pub fn length(&self) -> Option<u64> {
+        return match self {
+            ApiStream::Blob(_, _, length) => Some(*length),
+            ApiStream::Download(download) => download.length
+        };
+    }
+
+    pub fn into_stream(self) -> BlobStream {
+        return match self {
+            ApiStream::Blob(store, name, _) => futures::stream::once(async move {
+                return store.open_blob(&name, 0).await;
+            }).try_flatten().boxed(),
+            ApiStream::Download(download) => futures::stream::unfold(Reader::new(download), |mut reader| async move {
+                return reader.next_chunk().await.map(|chunk| (chunk, reader));
+            }).boxed()
+        };
+    }
+
+    /// Streams `length` bytes from `start` on. Blobs are opened at `start`, a running download
+    /// is read from its beginning and the bytes before `start` are dropped.
+    pub fn into_range_stream(self, start: u64, length: u64) -> BlobStream {
+        let (stream, skip) = match self {
+            ApiStream::Blob(store, name, _) => (futures::stream::once(async move {
+                return store.open_blob(&name, start).await;
+            }).try_flatten().boxed(), 0),
+            download => (download.into_stream(), start)
+        };
+
+        return futures::stream::unfold((stream, skip, length), |(mut stream, mut skip, remaining)| async move {
+            loop {
+                if remaining == 0 {
+                    return None;
+                }
+
+                let mut chunk = match stream.next().await? {
+                    Ok(chunk) => chunk,
+                    Err(error) => return Some((Err(error), (stream, skip, 0)))
+                };
+
+                if skip >= chunk.len() as u64 {
+                    skip -= chunk.len() as u64;
+                    continue;
+                }
+
+                chunk = chunk.slice(skip as usize..);
+                chunk.truncate(remaining.min(chunk.len() as u64) as usize);
+
+                let left = remaining - chunk.len() as u64;
+                return Some((Ok(chunk), (stream, 0, left)));
+            }
+        }).boxed();
+    }
+}
+
+
+enum Source {
+    Partial(File),
+    /// The download finished before this reader started, the stored blob is read instead.
+    Complete(BlobStream)
+}
+
+struct Reader {
+    download: Arc<Download>,
+    source: Option<Source>,
+    progress: watch::Receiver<DownloadState>,
+    offset: u64,
+    finished: bool,
+}
+
+impl Reader {
+    fn new(download: Arc<Download>) -> Self {
+        let progress = download.progress.clone();
+        return Self { download, source: None, progress, offset: 0, finished: false };
+    }
+
+    async fn open(&mut self) -> Result<Source, io::Error> {
+        let error = match File::open(&self.download.partial).await {
+            Ok(file) => return Ok(Source::Partial(file)),
+            Err(error) => error
+        };
+
+        // The partial file is gone once the blob is being stored, wait for that to finish.
+        loop {
+            let state = *self.progress.borrow_and_update();
+
+            match state {
+                DownloadState::Done(_) => break,
+                DownloadState::Failed => return Err(error),
+                DownloadState::Running(_) => {
+                    if self.progress.changed().await.is_err() && matches!(*self.progress.borrow(), DownloadState::Running(_)) {
+                        return Err(error);
+                    }
+                }
+            }
+        }
+
+        let complete = self.download.complete.lock().unwrap().clone();
+
+        return match complete {
+            Some(stream) => Ok(Source::Complete(stream.into_stream())),
+            None => Err(error)
+        };
+    }
+
+    fn fail(&mut self, error: io::Error) -> Option<Result<Bytes, io::Error>> {
+        self.finished = true;
+        return Some(Err(error));
+    }
+
+    async fn next_chunk(&mut self) -> Option<Result<Bytes, io::Error>> {
+        if self.finished {
+            return None;
+        }
+
+        if self.source.is_none() {
+            match self.open().await {
+                Ok(source) => self.source = Some(source),
+                Err(error) => return self.fail(error)
+            }
+        }
+
+        let file = match self.source.as_mut().unwrap() {
+            Source::Partial(file) => file,
+            Source::Complete(stream) => return stream.next().await
+        };
+
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+
+        loop {
+            let state = *self.progress.borrow_and_update();
+
+            let limit = match state {
+                DownloadState::Running(released) | DownloadState::Done(released) if released > self.offset => {
+                    CHUNK_SIZE.min((released - self.offset) as usize)
+                },
+                DownloadState::Done(_) => return None,
+                DownloadState::Failed => return self.fail(io::Error::other("upstream download failed")),
+                DownloadState::Running(_) => {
+                    if self.progress.changed().await.is_err() && matches!(*self.progress.borrow(), DownloadState::Running(_)) {
+                        return self.fail(io::Error::other("upstream download was abandoned"));
+                    }
+                    continue;
+                }
+            };
+
+            let read = match file.read(&mut buffer[..limit]).await {
+                Ok(read) => read,
+                Err(error) => return self.fail(error)
+            };
+
+            if read == 0 {
+                return self.fail(io::Error::other("partial file is shorter than announced"));
+            }
+
+            self.offset += read as u64;
+            buffer.truncate(read);
+            return Some(Ok(Bytes::from(buffer)));
+        }
+    }
+}
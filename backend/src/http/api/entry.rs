@@ -0,0 +1,141 @@
+use sha2::{Digest, Sha256};
+
+use crate::http::api::storage::ApiStorage;
+
+const MAGIC: &[u8; 4] = b"NPXC";
+const VERSION: u16 = 1;
+const HEADER_LENGTH: usize = MAGIC.len() + 2 + 8 + 32;
+
+
+#[derive(Debug)]
+pub enum EntryError {
+    Truncated,
+    UnknownFormat,
+    UnsupportedVersion(u16),
+    ChecksumMismatch,
+    Undecodable
+}
+
+impl std::fmt::Display for EntryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            EntryError::Truncated => write!(f, "entry is truncated"),
+            EntryError::UnknownFormat => write!(f, "entry has no cache header"),
+            EntryError::UnsupportedVersion(version) => write!(f, "entry has unsupported format version {version}"),
+            EntryError::ChecksumMismatch => write!(f, "entry checksum does not match"),
+            EntryError::Undecodable => write!(f, "entry payload does not decode")
+        };
+    }
+}
+
+/// Serializes a cache entry behind a header of magic, format version, payload length and
+/// the payload's sha256, so a torn or foreign file is recognised instead of misread.
+pub fn encode(stored: &ApiStorage) -> Vec<u8> {
+    let payload = serde_binary::to_vec(stored, serde_binary::binary_stream::Endian::Little).unwrap();
+
+    let mut result = Vec::with_capacity(HEADER_LENGTH + payload.len());
+    result.extend_from_slice(MAGIC);
+    result.extend_from_slice(&VERSION.to_le_bytes());
+    result.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    result.extend_from_slice(&Sha256::digest(&payload));
+    result.extend_from_slice(&payload);
+
+    return result;
+}
+
+pub fn decode(data: &[u8]) -> Result<ApiStorage, EntryError> {
+    if data.len() < HEADER_LENGTH {
+        return Err(EntryError::Truncated);
+    }
+
+    if &data[0..4] != MAGIC {
+        return Err(EntryError::UnknownFormat);
+    }
+
+    let version = u16::from_le_bytes([data[4], data[5]]);
+
+    if version != VERSION {
+        return Err(EntryError::UnsupportedVersion(version));
+    }
+
+    let length = u64::from_le_bytes(data[6..14].try_into().unwrap()) as usize;
+    let checksum = &data[14..HEADER_LENGTH];
+    let payload = &data[HEADER_LENGTH..];
+
+    if payload.len() != length {
+        return Err(EntryError::Truncated);
+    }
+
+    if Sha256::digest(payload).as_slice() != checksum {
+        return Err(EntryError::ChecksumMismatch);
+    }
+
+    return serde_binary::from_vec(payload.to_vec(), serde_binary::binary_stream::Endian::Little).map_err(|_| EntryError::Undecodable);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::http::api::integrity::Integrity;
+
+    use super::*;
+
+    fn stored() -> ApiStorage {
+        return ApiStorage {
+            headers: HashMap::from([("content-type".to_string(), b"application/json".to_vec())]),
+            body: b"{\"name\":\"lodash\"}".to_vec(),
+            stored_at: 1_700_000_000,
+            integrity: Integrity::from_sri("sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="),
+            stream: None
+        };
+    }
+
+    #[test]
+    fn round_trips() {
+        let decoded = decode(&encode(&stored())).unwrap();
+
+        assert_eq!(decoded.headers, stored().headers);
+        assert_eq!(decoded.body, stored().body);
+        assert_eq!(decoded.stored_at, stored().stored_at);
+        assert_eq!(decoded.integrity, stored().integrity);
+    }
+
+    #[test]
+    fn rejects_foreign_and_future_entries() {
+        let encoded = encode(&stored());
+
+        let mut foreign = encoded.clone();
+        foreign[0] = b'X';
+        assert!(matches!(decode(&foreign), Err(EntryError::UnknownFormat)));
+
+        let mut future = encoded.clone();
+        future[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(matches!(decode(&future), Err(EntryError::UnsupportedVersion(version)) if version == VERSION + 1));
+    }
+
+    #[test]
+    fn rejects_torn_entries() {
+        let encoded = encode(&stored());
+
+        assert!(matches!(decode(&encoded[..HEADER_LENGTH - 1]), Err(EntryError::Truncated)));
+        assert!(matches!(decode(&encoded[..encoded.len() - 1]), Err(EntryError::Truncated)));
+        assert!(matches!(decode(&[encoded.as_slice(), b"x"].concat()), Err(EntryError::Truncated)));
+    }
+
+    #[test]
+    fn rejects_changed_payloads() {
+        let mut flipped = encode(&stored());
+        let last = flipped.len() - 1;
+        flipped[last] ^= 1;
+        assert!(matches!(decode(&flipped), Err(EntryError::ChecksumMismatch)));
+
+        let mut garbage = encode(&stored());
+        let payload = b"not an entry".to_vec();
+        garbage.truncate(HEADER_LENGTH);
+        garbage[6..14].copy_from_slice(&(payload.len() as u64).to_le_bytes());
+        garbage[14..HEADER_LENGTH].copy_from_slice(&Sha256::digest(&payload));
+        garbage.extend_from_slice(&payload);
+        assert!(matches!(decode(&garbage), Err(EntryError::Undecodable)));
+    }
+}
@@ -1,6 +1,32 @@
+use axum::{http::StatusCode, response::{IntoResponse, Response}, Json};
+use serde_json::json;
 
 #[derive(Debug, Clone)]
 pub enum Error {
-    Api(),
+    /// Upstream answered with the contained (5xx) status code.
+    Api(u16),
+    /// Answered with the contained status and message, e.g. a refused publish.
+    Status(u16, String),
     Unknown()
 }
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            Error::Api(status) => write!(f, "upstream registry responded with {status}"),
+            Error::Status(_, message) => write!(f, "{message}"),
+            Error::Unknown() => write!(f, "upstream registry is unreachable")
+        };
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let status = match self {
+            Error::Status(status, _) => StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+            _ => StatusCode::BAD_GATEWAY
+        };
+
+        return (status, Json(json!({ "error": self.to_string() }))).into_response();
+    }
+}
@@ -0,0 +1,346 @@
+use std::{collections::{HashMap, HashSet}, sync::Arc, time::Duration};
+
+use tokio::sync::Mutex;
+
+use crate::{config::Config, http::api::{blobs::BlobStore, entry, freshness::FreshnessPolicy, integrity::Integrity, local::LOCAL_PREFIX, store::CacheStore}};
+
+
+#[derive(Clone, PartialEq)]
+struct Access {
+    /// Unix timestamp (seconds) of the last cache hit.
+    accessed: i64,
+    /// Size of the entry itself.
+    size: u64,
+    /// Tarball entries point at a blob and its length, the blob may be shared with other entries.
+    blob: Option<(Integrity, u64)>,
+}
+
+/// Tarball entries sharing one blob, evicted together.
+struct Candidate {
+    uris: Vec<String>,
+    integrity: Integrity,
+    accessed: i64,
+    size: u64,
+}
+
+pub struct EvictionPolicy {
+    /// Total bytes of entries and blobs the cache may hold, 0 for no limit.
+    pub max_bytes: u64,
+    /// How long a tarball may go without a hit before it is evicted, 0 for no limit.
+    pub max_age: Duration,
+    /// Between eviction runs, also how recently a tarball may have been used and still be
+    /// evicted, a client may still be reading it.
+    pub interval: Duration,
+    /// Encoded names of packages whose tarballs are never evicted.
+    pub pinned: HashSet<String>,
+}
+
+impl EvictionPolicy {
+    pub fn new(config: &Config) -> Self {
+        return Self {
+            max_bytes: config.cache_max_bytes,
+            max_age: Duration::from_secs(config.cache_max_age),
+            interval: Duration::from_secs(config.cache_eviction_interval).max(Duration::from_secs(1)),
+            pinned: config.cache_pinned.iter().map(|package| urlencoding::encode(package).to_string()).collect()
+        };
+    }
+
+    fn is_pinned(&self, uri: &str) -> bool {
+        return uri.split_once("/-/").is_some_and(|(package, _)| self.pinned.contains(package));
+    }
+}
+
+/// Tracks when every cache entry was last used and evicts the least recently used tarballs
+/// once the cache grows past its limits. Metadata and published tarballs are never evicted,
+/// metadata is small and needed to verify tarballs.
+pub struct CacheIndex {
+    store: Arc<dyn CacheStore>,
+    blobs: BlobStore,
+    policy: EvictionPolicy,
+    entries: Mutex<HashMap<String, Access>>,
+}
+
+impl CacheIndex {
+    pub fn new(config: &Config, store: Arc<dyn CacheStore>, blobs: BlobStore) -> Arc<Self> {
+        let index = Arc::new(Self {
+            store,
+            blobs,
+            policy: EvictionPolicy::new(config),
+            entries: Mutex::new(HashMap::new())
+        });
+
+        let index_clone = Arc::clone(&index);
+        tokio::spawn(async move {
+            index_clone.rebuild().await;
+
+            let mut interval = tokio::time::interval(index_clone.policy.interval);
+            loop {
+                interval.tick().await;
+                index_clone.evict().await;
+            }
+        });
+
+        return index;
+    }
+
+    pub async fn touch(&self, uri: &str, size: u64, blob: Option<(Integrity, u64)>) {
+        self.entries.lock().await.insert(uri.to_string(), Access { accessed: FreshnessPolicy::now(), size, blob });
+    }
+
+    pub async fn forget(&self, uri: &str) {
+        self.entries.lock().await.remove(uri);
+    }
+
+    /// Picks up entries a previous process stored. Their last hit is unknown, the time they
+    /// were stored stands in for it.
+    async fn rebuild(&self) {
+        let keys = match self.store.list().await {
+            Ok(keys) => keys,
+            Err(error) => {
+                println!("Could not list cache entries for eviction: {error}");
+                return;
+            }
+        };
+
+        for uri in keys {
+            let Ok(Some(value)) = self.store.load(&uri).await else {
+                continue;
+            };
+
+            let Ok(stored) = entry::decode(&value) else {
+                continue;
+            };
+
+            let mut blob = None;
+
+            if let Some(integrity) = stored.integrity {
+                let Ok(Some(stat)) = self.store.stat_blob(&BlobStore::name(&integrity)).await else {
+                    continue;
+                };
+
+                blob = Some((integrity, stat.length));
+            }
+
+            self.entries.lock().await.entry(uri).or_insert(Access { accessed: stored.stored_at, size: value.len() as u64, blob });
+        }
+    }
+
+    pub async fn evict(&self) {
+        if self.policy.max_bytes == 0 && self.policy.max_age.is_zero() {
+            return;
+        }
+
+        let entries = self.entries.lock().await.clone();
+        let mut total: u64 = entries.values().map(|access| access.size).sum();
+        let mut candidates: HashMap<String, Candidate> = HashMap::new();
+        let mut pinned: HashSet<String> = HashSet::new();
+
+        for (uri, access) in entries.iter() {
+            let Some((integrity, length)) = &access.blob else {
+                continue;
+            };
+
+            let name = BlobStore::name(integrity);
+
+            // Published packages have no upstream to fetch them from again.
+            if uri.starts_with(LOCAL_PREFIX) || self.policy.is_pinned(uri) {
+                pinned.insert(name.clone());
+            }
+
+            let candidate = candidates.entry(name).or_insert_with(|| {
+                total += length;
+                return Candidate { uris: vec![], integrity: integrity.clone(), accessed: access.accessed, size: *length };
+            });
+
+            candidate.uris.push(uri.clone());
+            candidate.accessed = candidate.accessed.max(access.accessed);
+            candidate.size += access.size;
+        }
+
+        let mut candidates: Vec<Candidate> = candidates.into_iter()
+            .filter(|(name, _)| !pinned.contains(name))
+            .map(|(_, candidate)| candidate)
+            .collect();
+        candidates.sort_by_key(|candidate| candidate.accessed);
+
+        let now = FreshnessPolicy::now();
+
+        for candidate in candidates {
+            let expired = !self.policy.max_age.is_zero() && now - candidate.accessed > self.policy.max_age.as_secs() as i64;
+            let over = self.policy.max_bytes != 0 && total > self.policy.max_bytes;
+
+            // Sorted by last hit, nothing after this one is older.
+            if !expired && !over || now - candidate.accessed < self.policy.interval.as_secs() as i64 {
+                break;
+            }
+
+            if !self.remove(&candidate, &entries).await {
+                continue;
+            }
+
+            println!("Evicted {} ({} bytes, last used {}s ago)", candidate.uris.join(", "), candidate.size, now - candidate.accessed);
+            total = total.saturating_sub(candidate.size);
+        }
+    }
+
+    /// Drops the candidate's entries and blob unless one of them was used since `seen`.
+    async fn remove(&self, candidate: &Candidate, seen: &HashMap<String, Access>) -> bool {
+        {
+            let mut entries = self.entries.lock().await;
+
+            if candidate.uris.iter().any(|uri| entries.get(uri) != seen.get(uri)) {
+                return false;
+            }
+
+            for uri in &candidate.uris {
+                entries.remove(uri);
+            }
+        }
+
+        for uri in &candidate.uris {
+            if let Err(error) = self.store.delete(uri).await {
+                println!("Could not evict {uri}: {error}");
+            }
+        }
+
+        self.blobs.remove(&candidate.integrity).await;
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::PathBuf};
+
+    use tokio::fs;
+
+    use crate::http::api::{integrity::{Algorithm, Hasher}, store::fs::FsStore};
+
+    use super::*;
+
+    struct Cache {
+        root: PathBuf,
+        index: CacheIndex,
+    }
+
+    impl Cache {
+        /// Every entry is 10 bytes and points at its own blob of 100 bytes, except the
+        /// metadata, which has none, and the mirror, which shares the published blob.
+        async fn new(max_bytes: u64) -> Self {
+            let root = env::temp_dir().join(format!("npm-proxy-eviction-{}", rand::random::<u64>()));
+            fs::create_dir_all(&root).await.unwrap();
+
+            let store: Arc<dyn CacheStore> = Arc::new(FsStore::new(&root));
+            let index = CacheIndex {
+                store: store.clone(),
+                blobs: BlobStore::new(store),
+                policy: EvictionPolicy { max_bytes, max_age: Duration::ZERO, interval: Duration::from_secs(60), pinned: HashSet::from(["pinned".to_string()]) },
+                entries: Mutex::new(HashMap::new())
+            };
+            let cache = Self { root, index };
+            let now = FreshnessPolicy::now();
+
+            cache.add("lodash", now - 5000, None).await;
+            cache.add("a/-/a-1.0.0.tgz", now - 1000, Some("a")).await;
+            cache.add("b/-/b-1.0.0.tgz", now - 500, Some("b")).await;
+            cache.add("c/-/c-1.0.0.tgz", now - 100, Some("c")).await;
+            cache.add("recent/-/recent-1.0.0.tgz", now - 10, Some("recent")).await;
+            cache.add(&(LOCAL_PREFIX.to_string() + "local/-/local-1.0.0.tgz"), now - 5000, Some("local")).await;
+            cache.add("mirror/-/local-1.0.0.tgz", now - 5000, Some("local")).await;
+            cache.add("pinned/-/pinned-1.0.0.tgz", now - 5000, Some("pinned")).await;
+
+            return cache;
+        }
+
+        async fn add(&self, uri: &str, accessed: i64, blob: Option<&str>) {
+            self.index.store.store(uri, vec![0; 10]).await.unwrap();
+
+            let blob = match blob {
+                Some(content) => {
+                    let integrity = Cache::integrity(content);
+                    let file = self.root.join("blob.tmp-test");
+                    fs::write(&file, Cache::content(content)).await.unwrap();
+                    self.index.blobs.commit(&file, &integrity).await.unwrap();
+                    Some((integrity, 100))
+                },
+                None => None
+            };
+
+            self.index.entries.lock().await.insert(uri.to_string(), Access { accessed, size: 10, blob });
+        }
+
+        fn content(blob: &str) -> Vec<u8> {
+            return blob.repeat(100).into_bytes()[..100].to_vec();
+        }
+
+        fn integrity(blob: &str) -> Integrity {
+            let mut hasher = Hasher::new(Algorithm::Sha512);
+            hasher.update(&Cache::content(blob));
+            return hasher.finish();
+        }
+
+        /// Whether the entry, and the blob it points at, are still stored.
+        async fn kept(&self, uri: &str, blob: &str) -> bool {
+            let entry = self.index.store.load(uri).await.unwrap().is_some();
+            let blob = self.index.store.stat_blob(&BlobStore::name(&Cache::integrity(blob))).await.unwrap().is_some();
+            assert_eq!(entry, self.index.entries.lock().await.contains_key(uri));
+            assert_eq!(entry, blob, "{uri} and its blob were not evicted together");
+            return entry;
+        }
+
+        async fn remove(self) {
+            fs::remove_dir_all(self.root).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn evicts_the_least_recently_used_tarballs_first() {
+        // 8 entries and 6 blobs are 680 bytes, evicting a and b brings them to 460.
+        let cache = Cache::new(460).await;
+        cache.index.evict().await;
+
+        assert!(!cache.kept("a/-/a-1.0.0.tgz", "a").await);
+        assert!(!cache.kept("b/-/b-1.0.0.tgz", "b").await);
+        assert!(cache.kept("c/-/c-1.0.0.tgz", "c").await);
+        assert!(cache.kept("recent/-/recent-1.0.0.tgz", "recent").await);
+        cache.remove().await;
+    }
+
+    #[tokio::test]
+    async fn never_evicts_metadata_published_pinned_or_recent_tarballs() {
+        let cache = Cache::new(1).await;
+        cache.index.evict().await;
+
+        assert!(!cache.kept("a/-/a-1.0.0.tgz", "a").await);
+        assert!(!cache.kept("b/-/b-1.0.0.tgz", "b").await);
+        assert!(!cache.kept("c/-/c-1.0.0.tgz", "c").await);
+        assert!(cache.kept("recent/-/recent-1.0.0.tgz", "recent").await);
+        assert!(cache.kept(&(LOCAL_PREFIX.to_string() + "local/-/local-1.0.0.tgz"), "local").await);
+        assert!(cache.kept("mirror/-/local-1.0.0.tgz", "local").await);
+        assert!(cache.kept("pinned/-/pinned-1.0.0.tgz", "pinned").await);
+        assert!(cache.index.store.load("lodash").await.unwrap().is_some());
+        cache.remove().await;
+    }
+
+    #[tokio::test]
+    async fn keeps_tarballs_used_during_the_run() {
+        let cache = Cache::new(1).await;
+        let seen = cache.index.entries.lock().await.clone();
+        let stale = Candidate { uris: vec!["a/-/a-1.0.0.tgz".to_string()], integrity: Cache::integrity("a"), accessed: 0, size: 110 };
+
+        cache.index.touch("a/-/a-1.0.0.tgz", 10, Some((Cache::integrity("a"), 100))).await;
+
+        assert!(!cache.index.remove(&stale, &seen).await);
+        assert!(cache.kept("a/-/a-1.0.0.tgz", "a").await);
+        cache.remove().await;
+    }
+
+    #[tokio::test]
+    async fn does_nothing_without_limits() {
+        let cache = Cache::new(0).await;
+        cache.index.evict().await;
+
+        assert!(cache.kept("a/-/a-1.0.0.tgz", "a").await);
+        cache.remove().await;
+    }
+}
@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+use chrono::Utc;
+
+use crate::{config::Config, http::api::storage::ApiStorage};
+
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ResourceKind {
+    /// Packuments and dist-tags, these change whenever something gets published upstream.
+    Metadata,
+    /// Tarballs never change once published, so they are cached forever.
+    Tarball
+}
+
+impl ResourceKind {
+    pub fn of(uri: &str) -> Self {
+        if !uri.starts_with("-/") && uri.contains("/-/") {
+            return ResourceKind::Tarball;
+        }
+
+        return ResourceKind::Metadata;
+    }
+}
+
+#[derive(Clone)]
+pub struct FreshnessPolicy {
+    pub metadata_ttl: Duration,
+    /// How long past `metadata_ttl` a stale entry is still answered immediately while it is refreshed in the background.
+    pub metadata_max_stale: Duration,
+}
+
+impl FreshnessPolicy {
+    pub fn new(config: &Config) -> Self {
+        return Self {
+            metadata_ttl: Duration::from_secs(config.metadata_ttl),
+            metadata_max_stale: Duration::from_secs(config.metadata_max_stale)
+        };
+    }
+
+    pub fn is_fresh(&self, kind: ResourceKind, stored: &ApiStorage) -> bool {
+        if kind == ResourceKind::Tarball {
+            return true;
+        }
+
+        return stored.age() < self.metadata_ttl;
+    }
+
+    pub fn is_within_stale_window(&self, stored: &ApiStorage) -> bool {
+        return stored.age() < self.metadata_ttl + self.metadata_max_stale;
+    }
+
+    pub fn now() -> i64 {
+        return Utc::now().timestamp();
+    }
+}
@@ -1,24 +1,79 @@
-use std::{collections::HashMap, path::PathBuf, pin::Pin, sync::Arc};
+use std::{collections::{HashMap, HashSet}, path::PathBuf, pin::Pin, sync::Arc, time::Duration};
 
-use base64::prelude::{BASE64_STANDARD, Engine};
-use reqwest::Url;
+use base64::prelude::{BASE64_URL_SAFE, Engine};
+use reqwest::{header::{HeaderMap, AUTHORIZATION, CONTENT_TYPE, IF_MODIFIED_SINCE, IF_NONE_MATCH}, StatusCode};
 use serde_json::Value;
-use tokio::{fs::{File, OpenOptions}, io::{AsyncReadExt, AsyncWriteExt}, sync::RwLock};
+use tokio::{fs::{self, File}, sync::{Mutex, RwLock}};
 
-use crate::http::api::{error::Error, storage::ApiStorage};
+use crate::http::api::{abbreviated, audit::{Audit, Endpoint}, blobs::BlobStore, compression::{self, Compression, Encoding}, credentials::Credentials, download::{Download, DownloadState}, entry, error::Error, eviction::CacheIndex, freshness::{FreshnessPolicy, ResourceKind}, integrity::{Algorithm, Hasher, Integrity}, local::{self, Precedence}, offline::Offline, policy::Policy, quarantine::Quarantine, search::SearchIndex, storage::ApiStorage, store::CacheStore, tags::DistTags, upstream::Upstreams};
 
 
 pub struct ApiInner {
-    pub registry_uri: String,
+    pub upstreams: Upstreams,
+
+    /// Upstream credentials from `PROXY_UPSTREAM_CREDENTIALS`, these win over an upstream's own `auth`.
+    pub credentials: Arc<Credentials>,
 
     pub resulting_registry_uri: String,
 
     pub cache: PathBuf,
+
+    pub freshness: FreshnessPolicy,
+
+    /// Uris that currently have a background revalidation running.
+    pub refreshing: Arc<Mutex<HashSet<String>>>,
+
+    /// Tarballs currently streaming from upstream into the cache, by uri.
+    pub downloads: Arc<Mutex<HashMap<String, Arc<Download>>>>,
+
+    pub store: Arc<dyn CacheStore>,
+
+    pub blobs: BlobStore,
+
+    /// Last use of every entry, for eviction.
+    pub index: Arc<CacheIndex>,
+
+    pub precedence: Precedence,
+
+    /// Patterns of packages that may be published even though upstream has them.
+    pub publish_over_upstream: Vec<String>,
+
+    /// Held while a publish reads and rewrites a local packument.
+    pub publishing: Arc<Mutex<()>>,
+
+    pub tags: DistTags,
+
+    pub policy: Arc<Policy>,
+
+    pub quarantine: Quarantine,
+
+    pub audit: Arc<Audit>,
+
+    pub offline: Offline,
+
+    pub search: SearchIndex,
+
+    /// Whether searches also ask the default registry.
+    pub search_upstream: bool,
+
+    pub compression: Compression,
+
+    /// Variant keys that are currently being compressed in the background.
+    pub compressing: Arc<Mutex<HashSet<String>>>,
 }
 
+/// A slow upstream search should not hold up the local results for long.
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Past this an audit is answered from a stale upstream answer, if there is one.
+const AUDIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+type LoadFuture = Pin<Box<dyn Future<Output = Result<ApiStorage, Error>> + Send>>;
+type LoadFn = Pin<Box<dyn Fn() -> LoadFuture + Send + Sync>>;
+
 pub struct ApiInnerResult {
     result: Arc<RwLock<Option<Result<ApiStorage, Error>>>>,
-    fnc: Pin<Box<dyn Fn() -> Pin<Box<dyn Future<Output = Result<ApiStorage, Error>> + Send + Sync>> + Send + Sync>>
+    fnc: LoadFn
 }
 
 impl ApiInnerResult {
@@ -39,8 +94,27 @@ impl Clone for ApiInner {
     fn clone(&self) -> Self {
         return ApiInner {
             cache: self.cache.clone(),
-            registry_uri: self.registry_uri.clone(),
-            resulting_registry_uri: self.resulting_registry_uri.clone()
+            upstreams: self.upstreams.clone(),
+            credentials: self.credentials.clone(),
+            resulting_registry_uri: self.resulting_registry_uri.clone(),
+            freshness: self.freshness.clone(),
+            refreshing: self.refreshing.clone(),
+            downloads: self.downloads.clone(),
+            store: self.store.clone(),
+            blobs: self.blobs.clone(),
+            index: self.index.clone(),
+            precedence: self.precedence,
+            publish_over_upstream: self.publish_over_upstream.clone(),
+            publishing: self.publishing.clone(),
+            tags: self.tags.clone(),
+            policy: self.policy.clone(),
+            quarantine: self.quarantine.clone(),
+            audit: self.audit.clone(),
+            offline: self.offline.clone(),
+            search: self.search.clone(),
+            search_upstream: self.search_upstream,
+            compression: self.compression.clone(),
+            compressing: self.compressing.clone()
         }
     }
 }
@@ -48,150 +122,1056 @@ impl Clone for ApiInner {
 
 impl ApiInner {
 
+    /// Points the tarball urls of a packument at the proxy. Bodies that are no json object and
+    /// versions without a tarball are left as they are.
     fn modified(registry_uri: String, resulting_registry_uri: String, data: Vec<u8>) -> Vec<u8> {
         let mut datar = data.clone();
-        let mut result: serde_json::Value = simd_json::serde::from_slice(&mut datar).unwrap();
-        if (result.as_object().unwrap().contains_key("versions")) {
-            let value = result.as_object_mut().unwrap().get_mut(&"versions".to_string()).unwrap().as_object_mut().unwrap().values_mut();
-
-            for entry in value  {
+        let Ok(mut result) = simd_json::serde::from_slice::<Value>(&mut datar) else {
+            return data;
+        };
 
-                let tarball = entry.as_object_mut().unwrap().get_mut(&"dist".to_string()).unwrap().as_object_mut().unwrap().get_mut(&"tarball".to_string()).unwrap();
-                if let Value::String(s) = tarball {
-                    *s = s.replace(&registry_uri, &resulting_registry_uri);
+        if let Some(versions) = result.get_mut("versions").and_then(|versions| versions.as_object_mut()) {
+            for entry in versions.values_mut() {
+                if let Some(Value::String(tarball)) = entry.get_mut("dist").and_then(|dist| dist.get_mut("tarball")) {
+                    *tarball = tarball.replace(&registry_uri, &resulting_registry_uri);
                 }
             }
         }
-        
-        return simd_json::to_vec(&result).unwrap();
+
+        return simd_json::to_vec(&result).unwrap_or(data);
     }
 
     pub fn do_load(self, uri: String) -> ApiInnerResult  {
 
+        let func: LoadFn = Box::pin(move || {
+            let uri_clone = uri.clone();
+            let me = self.clone();
 
-        let outer_self_registry = self.registry_uri.clone();
-        let outer_self_registry_result = self.resulting_registry_uri.clone();
+            Box::pin(async move {
+                return me.load_or_fetch(&uri_clone).await;
+            })
+        });
 
-        let func: Pin<Box<dyn Fn() -> Pin<Box<dyn Future<Output = Result<ApiStorage, Error>> + Send + Sync>> + Send + Sync>> = Box::pin(move || {
-            let uri_clone = uri.clone();
+        return ApiInnerResult { result: Arc::new(RwLock::new(None)), fnc: func };
+    }
 
-            let me = self.clone();
+    async fn load_or_fetch(&self, uri: &str) -> Result<ApiStorage, Error> {
+        self.check_policy(uri).await?;
 
-            let self_registry = outer_self_registry.clone();
-            let self_registry_result = outer_self_registry_result.clone();
+        if let Some(download) = self.downloads.lock().await.get(uri) {
+            return Ok(download.storage());
+        }
 
+        if ResourceKind::of(uri) == ResourceKind::Tarball {
+            return self.load_tarball(uri).await;
+        }
 
-            let raw = Box::pin(async move || {
-                if let Ok(val) = me.do_load_cache(&uri_clone).await {
-                    return Ok(val);
-                }
+        if let Some(package) = uri.strip_prefix("-/package/").and_then(|rest| rest.strip_suffix("/dist-tags")) {
+            return self.load_dist_tags(package).await;
+        }
 
-                let mut url = Url::parse(&me.registry_uri.clone()).unwrap();
-                url.set_path(&uri_clone);
-                let response = reqwest::Client::new().get(url).send().await;
+        if let Some(package) = uri.strip_prefix(abbreviated::PREFIX) {
+            return self.load_abbreviated(package).await;
+        }
 
-                if let Ok(val) = response {
-                    let status = val.status();
-                    let headers = val.headers().clone();
+        if uri.starts_with("-/") {
+            return self.load_metadata(uri).await;
+        }
 
-                    let mut headers_stored: HashMap<String, Vec<u8>> =  HashMap::new();
+        let (stored, published) = self.load_packument(uri).await?;
 
-                    for (given_key, value) in headers.iter() {
-                        if given_key.as_str().starts_with("content-") {
-                            continue;
-                        }
+        let stored = self.with_tag_overlay(uri, stored).await;
+        return Ok(self.without_hidden_versions(uri, stored, &published).await);
+    }
 
-                        if given_key.as_str().contains("cookie") {
-                            continue;
-                        }
+    /// The packument with versions published here merged in, nothing hidden yet. Also the
+    /// versions published here, those are not quarantined.
+    async fn load_packument(&self, uri: &str) -> Result<(ApiStorage, HashSet<String>), Error> {
+        let mut published = HashSet::new();
 
+        let stored = match self.load_local(uri).await {
+            Some(local) => {
+                published.extend(local["versions"].as_object().map(|versions| versions.keys().cloned().collect::<Vec<String>>()).unwrap_or_default());
+                self.merge_local(uri, local).await
+            },
+            None => self.load_metadata(uri).await?
+        };
 
-                        headers_stored.insert(given_key.to_string().clone(), value.as_bytes().to_vec());
-                    }
+        return Ok((stored, published));
+    }
 
-                    let mut body = val.bytes().await.unwrap().to_vec();
+    /// Refuses denied packages and tarballs of denied versions before anything is fetched.
+    async fn check_policy(&self, uri: &str) -> Result<(), Error> {
+        let uri = uri.strip_prefix(abbreviated::PREFIX).unwrap_or(uri);
 
-                    if headers.contains_key("content-type") {
-                        let given_type = headers.get("content-type").unwrap();
-                        if given_type.to_str().unwrap().contains("json") {
-                            body = ApiInner::modified(self_registry.clone(), self_registry_result.clone(), body);
-                        }
-                        headers_stored.insert("content-type".to_string(), given_type.as_bytes().to_vec());
-                    }
+        if uri.starts_with("-/") && !uri.starts_with("-/package/") {
+            return Ok(());
+        }
 
-                    if headers.contains_key("content-disposition") {
-                        let given_type = headers.get("content-disposition").unwrap();
-                        headers_stored.insert("content-disposition".to_string(), given_type.as_bytes().to_vec());
-                    }
+        let package = Upstreams::package_of(uri);
+        let version = uri.split_once("/-/")
+            .and_then(|(_, file)| urlencoding::decode(file).ok())
+            .and_then(|file| Policy::tarball_version(&package, &file));
 
-                    if headers.contains_key("content-transfer-encoding") {
-                        let given_type = headers.get("content-transfer-encoding").unwrap();
-                        headers_stored.insert("content-transfer-encoding".to_string(), given_type.as_bytes().to_vec());
-                    }
+        return self.policy.check(&package, version.as_deref()).await.map_err(|violation| Error::Status(403, violation));
+    }
 
-                    headers_stored.remove("accept-ranges");
-                    headers_stored.remove("server");
-                    headers_stored.remove("connection");
-                    headers_stored.remove("vary");
+    /// Leaves out versions the policy denies and versions still in quarantine.
+    async fn without_hidden_versions(&self, uri: &str, mut stored: ApiStorage, published: &HashSet<String>) -> ApiStorage {
+        let package = Upstreams::package_of(uri);
 
-                    let stored = ApiStorage {
-                        body: body,
-                        headers: headers_stored
-                    };
+        let Ok(mut packument) = serde_json::from_slice::<Value>(&stored.body) else {
+            return stored;
+        };
 
-                    me.try_cache(status, uri_clone.clone(), &stored).await;
-                    return Ok(stored);
-                }
+        let hidden = self.hidden_versions(&package, &packument, published).await;
 
-                if let Err(val) = response {
-                    println!("{}", val.to_string());
-                }
+        if !hidden.is_empty() {
+            ApiInner::hide_versions(&mut packument, &hidden);
+            stored.body = serde_json::to_vec(&packument).unwrap();
+        }
+
+        return stored;
+    }
+
+    async fn hidden_versions(&self, package: &str, packument: &Value, published: &HashSet<String>) -> Vec<String> {
+        let mut hidden = self.policy.denied_versions(package, packument).await;
+        hidden.extend(self.quarantine.held_back(package, packument, published).await);
+        return hidden;
+    }
+
+    /// Indexes a packument as installs see it, so search does not find denied packages and
+    /// never shows a denied or quarantined version as the one to install.
+    async fn index_for_search(&self, uri: &str, mut packument: Value) {
+        let local = uri.starts_with(local::LOCAL_PREFIX);
+        let package = Upstreams::package_of(uri.strip_prefix(local::LOCAL_PREFIX).unwrap_or(uri));
+
+        if self.policy.check(&package, None).await.is_err() {
+            self.search.remove(&package).await;
+            return;
+        }
+
+        // Versions published here are not quarantined.
+        let published: HashSet<String> = match local {
+            true => packument["versions"].as_object().map(|versions| versions.keys().cloned().collect()).unwrap_or_default(),
+            false => HashSet::new()
+        };
+
+        let hidden = self.hidden_versions(&package, &packument, &published).await;
+
+        if !hidden.is_empty() {
+            ApiInner::hide_versions(&mut packument, &hidden);
+        }
+
+        self.search.update(&packument).await;
+    }
+
+    /// Indexes the packuments already in the cache, at startup.
+    pub async fn rebuild_search(&self) {
+        let Ok(keys) = self.store.list().await else {
+            return;
+        };
+
+        for uri in keys.into_iter().filter(|uri| SearchIndex::indexes(uri)) {
+            let Ok(Some(value)) = self.store.load(&uri).await else {
+                continue;
+            };
+
+            if let Ok(stored) = entry::decode(&value) && let Ok(packument) = serde_json::from_slice::<Value>(&stored.body) {
+                self.index_for_search(&uri, packument).await;
+            }
+        }
+    }
+
+    /// Removes versions from a packument. Tags pointing at them are dropped, a hidden `latest`
+    /// moves back to the highest remaining release below it, never up to a newer major.
+    pub fn hide_versions(packument: &mut Value, hidden: &[String]) {
+        let Some(versions) = packument.get_mut("versions").and_then(|versions| versions.as_object_mut()) else {
+            return;
+        };
+
+        versions.retain(|version, _| !hidden.contains(version));
+
+        let hidden_latest = packument.get("dist-tags").and_then(|tags| tags.get("latest")).and_then(|latest| latest.as_str())
+            .filter(|latest| hidden.iter().any(|hidden| hidden == latest))
+            .and_then(|latest| semver::Version::parse(latest).ok());
+
+        let latest = packument.get("versions").and_then(|versions| versions.as_object()).into_iter().flat_map(|versions| versions.keys())
+            .filter_map(|version| semver::Version::parse(version).ok())
+            .filter(|version| version.pre.is_empty() && hidden_latest.as_ref().is_none_or(|hidden_latest| version < hidden_latest))
+            .max()
+            .map(|version| version.to_string());
+
+        if let Some(time) = packument.get_mut("time").and_then(|time| time.as_object_mut()) {
+            time.retain(|version, _| !hidden.contains(version));
+        }
+
+        if let Some(tags) = packument.get_mut("dist-tags").and_then(|tags| tags.as_object_mut()) {
+            tags.retain(|_, version| !version.as_str().is_some_and(|version| hidden.iter().any(|hidden| hidden == version)));
+
+            if !tags.contains_key("latest") && let Some(latest) = latest {
+                tags.insert("latest".to_string(), Value::String(latest));
+            }
+        }
+    }
+
+    async fn with_tag_overlay(&self, uri: &str, mut stored: ApiStorage) -> ApiStorage {
+        let overlay = self.tags.for_package(uri).await;
+
+        if overlay.is_empty() {
+            return stored;
+        }
+
+        if let Ok(mut packument) = serde_json::from_slice::<Value>(&stored.body) && packument.get("versions").is_some() {
+            DistTags::apply(&mut packument, &overlay);
+            stored.body = serde_json::to_vec(&packument).unwrap();
+        }
+
+        return stored;
+    }
+
+    /// Answers dist-tags from the packument, so local versions and overlay tags are included.
+    async fn load_dist_tags(&self, package: &str) -> Result<ApiStorage, Error> {
+        let packument = Box::pin(self.load_or_fetch(package)).await?;
+        let document: Value = serde_json::from_slice(&packument.body).map_err(|_| Error::Status(404, "package not found".to_string()))?;
+
+        let Some(tags) = document.get("dist-tags") else {
+            return Err(Error::Status(404, "package not found".to_string()));
+        };
+
+        return Ok(ApiStorage {
+            headers: HashMap::from([("content-type".to_string(), b"application/json".to_vec())]),
+            body: serde_json::to_vec(tags).unwrap(),
+            stored_at: packument.stored_at,
+            integrity: None,
+            stream: None
+        });
+    }
+
+    /// Derived from the full packument, so the cache holds one document per package and
+    /// both forms always agree.
+    async fn load_abbreviated(&self, package: &str) -> Result<ApiStorage, Error> {
+        let mut stored = Box::pin(self.load_or_fetch(package)).await?;
+
+        let Ok(packument) = serde_json::from_slice::<Value>(&stored.body) else {
+            return Ok(stored);
+        };
+
+        // Errors like `{"error": "Not found"}` pass through as they are.
+        if packument.get("versions").is_none() {
+            return Ok(stored);
+        }
+
+        stored.body = serde_json::to_vec(&abbreviated::abbreviate(&packument)).unwrap();
+        stored.headers.insert("content-type".to_string(), abbreviated::CONTENT_TYPE.as_bytes().to_vec());
+        // The etag is the one of the full document.
+        stored.headers.remove("etag");
+
+        return Ok(stored);
+    }
+
+    pub async fn release_quarantined(&self, package: &str, version: &str) -> Result<(), Error> {
+        if let Err(error) = self.quarantine.release(package, version).await {
+            println!("Could not release {package}@{version}: {error}");
+            return Err(Error::Status(500, "could not store the release".to_string()));
+        }
+
+        println!("Released {package}@{version} from quarantine");
+        return Ok(());
+    }
+
+    /// Adds or moves an overlay tag. The version has to exist, locally or upstream.
+    pub async fn set_dist_tag(&self, package: &str, tag: &str, version: Option<String>) -> Result<ApiStorage, Error> {
+        let uri = urlencoding::encode(package).to_string();
+
+        if tag.is_empty() || tag.starts_with(|first: char| first.is_ascii_digit()) || tag.starts_with('v') && tag[1..].starts_with(|first: char| first.is_ascii_digit()) {
+            return Err(Error::Status(400, format!("{tag} looks like a version, it cannot be used as a tag")));
+        }
+
+        if version.is_none() && tag == "latest" {
+            return Err(Error::Status(400, "the latest tag cannot be removed".to_string()));
+        }
+
+        let packument = self.load_or_fetch(&uri).await?;
+        let document: Value = serde_json::from_slice(&packument.body).unwrap_or_default();
+
+        match &version {
+            Some(version) if document["versions"].get(version).is_none() => {
+                return Err(Error::Status(404, format!("{package}@{version} does not exist")));
+            },
+            None if document["dist-tags"].get(tag).is_none() => {
+                return Err(Error::Status(404, format!("{package} has no dist-tag {tag}")));
+            },
+            _ => {}
+        }
+
+        if let Err(error) = self.tags.set(&uri, tag, version).await {
+            println!("Could not store dist-tag {tag} of {package}: {error}");
+            return Err(Error::Status(500, "could not store the dist-tag".to_string()));
+        }
+
+        return self.load_dist_tags(&uri).await;
+    }
+
+    async fn load_metadata(&self, uri: &str) -> Result<ApiStorage, Error> {
+        let cached = self.do_load_cache(uri).await.ok();
+
+        if self.offline.enabled {
+            return match cached {
+                Some(stored) => Ok(stored),
+                None => Err(self.offline.miss(uri).await)
+            };
+        }
+
+        if let Some(stored) = &cached {
+            if self.freshness.is_fresh(ResourceKind::of(uri), stored) {
+                return Ok(stored.clone());
+            }
+
+            if self.freshness.is_within_stale_window(stored) {
+                self.refresh_in_background(uri.to_string(), stored.clone()).await;
+                return Ok(stored.clone());
+            }
+        }
+
+        let result = self.fetch(uri, cached.clone(), None).await;
+
+        // Upstream being down should not break installs of anything we have seen before,
+        // no matter how old our copy is.
+        if let Err(error) = &result && let Some(stored) = cached {
+            println!("Serving stale {uri} because upstream failed: {error:?}");
+            return Ok(stored);
+        }
+
+        return result;
+    }
+
+    async fn load_local(&self, uri: &str) -> Option<Value> {
+        let stored = self.do_load_cache(&local::key(uri)).await.ok()?;
+        return serde_json::from_slice(&stored.body).ok();
+    }
+
+    /// Serves a package published here together with what upstream has under the same name.
+    async fn merge_local(&self, uri: &str, local: Value) -> ApiStorage {
+        let upstream = match self.precedence {
+            Precedence::LocalOnly => None,
+            // Most local packages do not exist upstream, that is no miss worth reporting.
+            _ if self.offline.enabled => self.do_load_cache(uri).await.ok().and_then(|stored| serde_json::from_slice(&stored.body).ok()),
+            _ => self.load_metadata(uri).await.ok().and_then(|stored| serde_json::from_slice(&stored.body).ok())
+        };
+
+        return ApiStorage {
+            headers: HashMap::from([("content-type".to_string(), b"application/json".to_vec())]),
+            body: serde_json::to_vec(&local::merge(local, upstream, self.precedence)).unwrap(),
+            stored_at: FreshnessPolicy::now(),
+            integrity: None,
+            stream: None
+        };
+    }
+
+    /// Whether upstream has a package of that name, from the cached copy when offline.
+    async fn exists_upstream(&self, uri: &str) -> Result<bool, Error> {
+        let upstream = match self.offline.enabled {
+            true => self.do_load_cache(uri).await.ok(),
+            false => match self.load_metadata(uri).await {
+                Ok(stored) => Some(stored),
+                Err(Error::Status(404, _)) => None,
+                Err(error) => return Err(Error::Status(503, format!("could not check whether the package exists upstream, {error}")))
+            }
+        };
+
+        return Ok(upstream
+            .and_then(|stored| serde_json::from_slice::<Value>(&stored.body).ok())
+            .and_then(|packument| packument.get("versions").and_then(|versions| versions.as_object()).map(|versions| !versions.is_empty()))
+            .unwrap_or(false));
+    }
+
+    /// Stores the packument and tarballs of an `npm publish` as a local package.
+    pub async fn publish(&self, package: &str, payload: Value) -> Result<(), Error> {
+        // Nothing the policy denies gets in by being published here either.
+        self.policy.check(package, None).await.map_err(|violation| Error::Status(403, violation))?;
+
+        if let Some(Value::Object(versions)) = payload.get("versions") {
+            for version in versions.keys() {
+                self.policy.check(package, Some(version)).await.map_err(|violation| Error::Status(403, violation))?;
+            }
+        }
+
+        let uri = urlencoding::encode(package).to_string();
+
+        // A version published under an upstream name would be picked up by ranges meant for
+        // the real package.
+        if !self.publish_over_upstream.iter().any(|pattern| Upstreams::matches(pattern, package)) && self.exists_upstream(&uri).await? {
+            return Err(Error::Status(403, format!("{package} exists upstream and may not be published here")));
+        }
+
+        let _publishing = self.publishing.lock().await;
+
+        let tarball_base = self.resulting_registry_uri.clone() + package + "/-/";
+
+        let (mut packument, attachments) = local::apply_publish(self.load_local(&uri).await, &payload, package, &tarball_base)
+            .map_err(|local::Rejection(status, message)| Error::Status(status, message))?;
+
+        for attachment in attachments {
+            let dist = &mut packument["versions"][&attachment.version]["dist"];
+            let expected = Integrity::from_dist(dist);
+
+            let mut hasher = Hasher::new(expected.as_ref().map(|expected| expected.algorithm).unwrap_or(Algorithm::Sha512));
+            hasher.update(&attachment.data);
+            let actual = hasher.finish();
+
+            match &expected {
+                Some(expected) if *expected != actual => {
+                    return Err(Error::Status(400, format!("{} does not match its integrity {expected}", attachment.file_name)));
+                },
+                Some(_) => {},
+                None => dist["integrity"] = Value::String(actual.to_string())
+            }
+
+            let file = self.cache.join(format!(".tmp-{}", uuid::Uuid::new_v4()));
+            let committed = match fs::write(&file, &attachment.data).await {
+                Ok(()) => self.blobs.commit(&file, &actual).await,
+                Err(error) => Err(error)
+            };
+
+            if let Err(error) = committed {
+                println!("Could not store {}: {error}", attachment.file_name);
+                let _ = fs::remove_file(&file).await;
+                return Err(Error::Status(500, "could not store the tarball".to_string()));
+            }
+
+            let tarball_uri = uri.clone() + "/-/" + &urlencoding::encode(&attachment.file_name);
+
+            // An upstream tarball cached under the same name would be served instead.
+            self.index.forget(&tarball_uri).await;
+            let _ = self.store.delete(&tarball_uri).await;
+
+            let entry = ApiStorage {
+                headers: HashMap::from([("content-type".to_string(), b"application/octet-stream".to_vec())]),
+                body: vec![],
+                stored_at: FreshnessPolicy::now(),
+                stream: Some(self.blobs.stream(&actual, attachment.data.len() as u64)),
+                integrity: Some(actual)
+            };
+
+            self.write_cache(local::key(&tarball_uri), &entry).await;
+        }
+
+        let stored = ApiStorage {
+            headers: HashMap::from([("content-type".to_string(), b"application/json".to_vec())]),
+            body: serde_json::to_vec(&packument).unwrap(),
+            stored_at: FreshnessPolicy::now(),
+            integrity: None,
+            stream: None
+        };
+
+        let value = entry::encode(&stored);
+        let size = value.len() as u64;
+
+        if let Err(error) = self.store.store(&local::key(&uri), value).await {
+            println!("Could not store packument of {package}: {error}");
+            return Err(Error::Status(500, "could not store the packument".to_string()));
+        }
+
+        self.index.touch(&local::key(&uri), size, None).await;
+        self.index_for_search(&local::key(&uri), packument).await;
+
+        println!("Published {package}");
+        return Ok(());
+    }
+
+    /// Searches the cached packuments and, when enabled, the default registry.
+    pub async fn search(&self, text: &str, from: usize, size: usize) -> Value {
+        let wanted = from.saturating_add(size);
+        let (local, total) = self.search.search(text, 0, wanted).await;
+        let (local, denied) = self.allowed_results(local).await;
+
+        let mut upstream = match self.search_upstream && !self.offline.enabled {
+            true => self.search_upstream(text, wanted).await,
+            false => None
+        };
+
+        if let Some(upstream) = &mut upstream && let Some(objects) = upstream.get_mut("objects").map(Value::take) {
+            let (objects, denied) = self.allowed_results(objects.as_array().cloned().unwrap_or_default()).await;
+            upstream["total"] = Value::from(upstream["total"].as_u64().unwrap_or(0).saturating_sub(denied as u64));
+            upstream["objects"] = Value::Array(objects);
+        }
+
+        return SearchIndex::merge(local, total.saturating_sub(denied), upstream, from, size);
+    }
+
+    /// Leaves out results of packages the policy denies, it may have changed since they were
+    /// indexed and upstream knows nothing of it. The results kept and how many were left out.
+    async fn allowed_results(&self, objects: Vec<Value>) -> (Vec<Value>, usize) {
+        let mut allowed = Vec::with_capacity(objects.len());
+        let mut denied = 0;
+
+        for object in objects {
+            match object["package"]["name"].as_str() {
+                Some(name) if self.policy.check(name, None).await.is_err() => denied += 1,
+                _ => allowed.push(object)
+            }
+        }
+
+        return (allowed, denied);
+    }
+
+    async fn search_upstream(&self, text: &str, size: usize) -> Option<Value> {
+        let upstream = self.upstreams.fallback();
+        let mut url = upstream.url_for("-/v1/search");
+        url.query_pairs_mut().append_pair("text", text).append_pair("size", &size.to_string());
+
+        let mut request = reqwest::Client::new().get(url).timeout(SEARCH_TIMEOUT);
+
+        if let Some(auth) = self.credentials.header_for(&upstream.url).await.or(upstream.auth.clone()) {
+            request = request.header(AUTHORIZATION, auth);
+        }
 
+        let response = match request.send().await.and_then(|response| response.error_for_status()) {
+            Ok(response) => response,
+            Err(error) => {
+                println!("Upstream search failed: {}", error.without_url());
+                return None;
+            }
+        };
+
+        return serde_json::from_slice(&response.bytes().await.ok()?).ok();
+    }
+
+    /// Answers `npm audit` from the imported advisory database or the default registry. An
+    /// upstream answer is reused for the same request while fresh, and served stale when the
+    /// registry fails.
+    pub async fn audit(&self, endpoint: Endpoint, request: Vec<u8>) -> Result<ApiStorage, Error> {
+        if self.audit.is_local() {
+            let request: Value = serde_json::from_slice(&request).map_err(|error| Error::Status(400, format!("invalid audit request: {error}")))?;
+
+            let report = match endpoint {
+                Endpoint::Bulk => self.audit.bulk(&request).await.map_err(|message| Error::Status(400, message))?,
+                Endpoint::Quick => self.audit.quick(&request).await
+            };
+
+            return Ok(Audit::json(&report));
+        }
+
+        let key = Audit::key(endpoint, &request);
+        let kept = self.audit.answer(&key).await;
+
+        if let Some((answer, true)) = kept {
+            return Ok(answer);
+        }
+
+        if self.offline.enabled {
+            return kept.map(|(answer, _)| answer).ok_or(Error::Status(404, "Not found, audits need PROXY_AUDIT_DATABASE while the proxy is offline".to_string()));
+        }
+
+        return match self.audit_upstream(endpoint, request).await {
+            Ok(answer) => {
+                self.audit.remember(key, answer.clone()).await;
+                Ok(answer)
+            },
+            Err(error @ (Error::Api(_) | Error::Unknown())) => kept.map(|(answer, _)| answer).ok_or(error),
+            Err(error) => Err(error)
+        };
+    }
+
+    async fn audit_upstream(&self, endpoint: Endpoint, request: Vec<u8>) -> Result<ApiStorage, Error> {
+        let upstream = self.upstreams.fallback();
+        let mut builder = reqwest::Client::new().post(upstream.url_for(endpoint.path()))
+            .timeout(AUDIT_TIMEOUT)
+            .header(CONTENT_TYPE, "application/json")
+            .body(request);
+
+        if let Some(auth) = self.credentials.header_for(&upstream.url).await.or(upstream.auth.clone()) {
+            builder = builder.header(AUTHORIZATION, auth);
+        }
+
+        let response = builder.send().await.map_err(|error| {
+            println!("Upstream audit failed: {}", error.without_url());
+            Error::Unknown()
+        })?;
+
+        let status = response.status();
+        let body = response.bytes().await.map_err(|_| Error::Unknown())?;
+
+        if status.is_server_error() {
+            return Err(Error::Api(status.as_u16()));
+        }
+
+        if !status.is_success() {
+            return Err(Error::Status(status.as_u16(), String::from_utf8_lossy(&body).to_string()));
+        }
+
+        let mut answer = Audit::json(&Value::Null);
+        answer.body = body.to_vec();
+        return Ok(answer);
+    }
+
+    /// Compresses a document loaded from `uri` for the client. Large ones are served from a
+    /// variant compressed once at the best level and kept in the cache, the variant is only
+    /// used while it was made from the very same body.
+    pub async fn compress(&self, uri: &str, mut stored: ApiStorage, encoding: Option<Encoding>) -> ApiStorage {
+        let Some(encoding) = encoding.filter(|_| self.compression.applies_to(&stored)) else {
+            return stored;
+        };
+
+        let mut variant = None;
+
+        if stored.body.len() >= self.compression.precompress_min_size {
+            let key = encoding.key(uri);
+            let digest = Compression::digest(&stored.body);
+
+            variant = match self.store.load(&key).await {
+                Ok(Some(value)) => entry::decode(&value).ok().filter(|variant| variant.header(compression::SOURCE_HEADER) == Some(&digest)),
+                _ => None
+            };
+
+            if variant.is_none() {
+                self.compress_in_background(key, encoding, stored.body.clone(), digest).await;
+            }
+        }
+
+        stored.body = match variant {
+            Some(variant) => variant.body,
+            None => {
+                let body = std::mem::take(&mut stored.body);
+                tokio::task::spawn_blocking(move || encoding.compress(&body, false)).await.unwrap()
+            }
+        };
+
+        stored.headers.insert("content-encoding".to_string(), encoding.name().as_bytes().to_vec());
+        return stored;
+    }
+
+    async fn compress_in_background(&self, key: String, encoding: Encoding, body: Vec<u8>, digest: Vec<u8>) {
+        if !self.compressing.lock().await.insert(key.clone()) {
+            return;
+        }
+
+        let me = self.clone();
+        tokio::spawn(async move {
+            let compressed = tokio::task::spawn_blocking(move || encoding.compress(&body, true)).await.unwrap();
+
+            let variant = ApiStorage {
+                headers: HashMap::from([(compression::SOURCE_HEADER.to_string(), digest)]),
+                body: compressed,
+                stored_at: FreshnessPolicy::now(),
+                integrity: None,
+                stream: None
+            };
+
+            me.write_cache(key.clone(), &variant).await;
+            me.compressing.lock().await.remove(&key);
+        });
+    }
+
+    async fn refresh_in_background(&self, uri: String, stale: ApiStorage) {
+        if !self.refreshing.lock().await.insert(uri.clone()) {
+            return;
+        }
+
+        let me = self.clone();
+        tokio::spawn(async move {
+            if let Err(error) = me.fetch(&uri, Some(stale), None).await {
+                println!("Background refresh of {uri} failed: {error:?}");
+            }
+
+            me.refreshing.lock().await.remove(&uri);
+        });
+    }
+
+    async fn load_tarball(&self, uri: &str) -> Result<ApiStorage, Error> {
+        if let Ok(stored) = self.do_load_cache(uri).await {
+            return Ok(stored);
+        }
+
+        let expected = self.expected_integrity(uri).await;
+
+        // The same content may already be stored for another uri.
+        if let Some(integrity) = &expected && let Some(length) = self.blobs.verify(integrity).await {
+            let stored = ApiStorage {
+                headers: HashMap::from([("content-type".to_string(), b"application/octet-stream".to_vec())]),
+                body: vec![],
+                stored_at: FreshnessPolicy::now(),
+                integrity: Some(integrity.clone()),
+                stream: Some(self.blobs.stream(integrity, length))
+            };
+
+            self.do_cache(uri.to_string(), &stored).await;
+            return Ok(stored);
+        }
+
+        if self.offline.enabled {
+            return Err(self.offline.miss(uri).await);
+        }
+
+        return self.fetch(uri, None, expected).await;
+    }
+
+    /// Looks up the digest the packument announces for the tarball at `uri`.
+    async fn expected_integrity(&self, uri: &str) -> Option<Integrity> {
+        let (package, file) = uri.split_once("/-/")?;
+        let suffix = "/-/".to_string() + &urlencoding::decode(file).ok()?;
+
+        // Unfiltered, versions in quarantine or denied by the policy are the ones most often
+        // pinned by lockfiles and have to be verified as well.
+        let (packument, _) = Box::pin(self.load_packument(package)).await.ok()?;
+        let document: Value = serde_json::from_slice(&packument.body).ok()?;
+
+        return document.get("versions")?.as_object()?.values()
+            .filter_map(|version| version.get("dist"))
+            .find(|dist| dist.get("tarball").and_then(|tarball| tarball.as_str()).is_some_and(|tarball| tarball.ends_with(&suffix)))
+            .and_then(Integrity::from_dist);
+    }
+
+    /// Fetches `uri` from upstream. When a stale copy is given, the request is made conditional
+    /// on its `etag`/`last-modified` so an unchanged resource only costs a 304.
+    async fn fetch(&self, uri: &str, cached: Option<ApiStorage>, expected: Option<Integrity>) -> Result<ApiStorage, Error> {
+        let upstream = self.upstreams.for_uri(uri);
+        let mut request = reqwest::Client::new().get(upstream.url_for(uri));
+
+        if let Some(auth) = self.credentials.header_for(&upstream.url).await.or(upstream.auth.clone()) {
+            request = request.header(AUTHORIZATION, auth);
+        }
+
+        if let Some(stored) = &cached {
+            if let Some(etag) = stored.header("etag") {
+                request = request.header(IF_NONE_MATCH, etag.clone());
+            }
+
+            if let Some(last_modified) = stored.header("last-modified") {
+                request = request.header(IF_MODIFIED_SINCE, last_modified.clone());
+            }
+        }
+
+        let response = request.send().await;
+
+        if let Err(val) = response {
+            println!("Request for {uri} failed: {}", val.without_url());
+            return Err(Error::Unknown());
+        }
+
+        let val = response.unwrap();
+        let status = val.status();
+
+        if status.is_server_error() {
+            return Err(Error::Api(status.as_u16()));
+        }
+
+        if status == StatusCode::NOT_MODIFIED && let Some(mut stored) = cached {
+            stored.stored_at = FreshnessPolicy::now();
+            self.do_cache(uri.to_string(), &stored).await;
+            return Ok(stored);
+        }
+
+        let headers = val.headers().clone();
+        let mut headers_stored = ApiInner::stored_headers(&headers);
+
+        if status.is_success() && ResourceKind::of(uri) == ResourceKind::Tarball {
+            return self.stream_to_cache(uri, val, headers_stored, expected).await;
+        }
+
+        // A connection dropped mid-body is a failed request like any other, stale copies
+        // are served for it.
+        let mut body = match val.bytes().await {
+            Ok(body) => body.to_vec(),
+            Err(error) => {
+                println!("Reading the response for {uri} failed: {}", error.without_url());
                 return Err(Error::Unknown());
-            });
+            }
+        };
 
-            Box::pin(async move {
-                let response = raw().await;
-                return response.clone();
-            })
+        if let Some(given_type) = headers.get("content-type") {
+            if given_type.to_str().is_ok_and(|given_type| given_type.contains("json")) {
+                body = ApiInner::modified(upstream.url.clone(), self.resulting_registry_uri.clone(), body);
+            }
+            headers_stored.insert("content-type".to_string(), given_type.as_bytes().to_vec());
+        }
+
+        let stored = ApiStorage {
+            body,
+            headers: headers_stored,
+            stored_at: FreshnessPolicy::now(),
+            integrity: None,
+            stream: None
+        };
+
+        self.try_cache(status, uri.to_string(), &stored).await;
+        return Ok(stored);
+    }
+
+    fn stored_headers(headers: &HeaderMap) -> HashMap<String, Vec<u8>> {
+        let mut headers_stored: HashMap<String, Vec<u8>> =  HashMap::new();
+
+        for (given_key, value) in headers.iter() {
+            if given_key.as_str().starts_with("content-") {
+                continue;
+            }
+
+            if given_key.as_str().contains("cookie") {
+                continue;
+            }
+
+
+            headers_stored.insert(given_key.to_string().clone(), value.as_bytes().to_vec());
+        }
+
+        for kept in ["content-type", "content-disposition", "content-transfer-encoding"] {
+            if let Some(given_type) = headers.get(kept) {
+                headers_stored.insert(kept.to_string(), given_type.as_bytes().to_vec());
+            }
+        }
+
+        headers_stored.remove("accept-ranges");
+        headers_stored.remove("server");
+        headers_stored.remove("connection");
+        headers_stored.remove("vary");
+        headers_stored.remove("authorization");
+        headers_stored.remove("proxy-authorization");
+
+        return headers_stored;
+    }
+
+    /// Hands out the tarball while it is still being written into the cache. A second request
+    /// for a tarball that is already downloading attaches to that download instead.
+    async fn stream_to_cache(&self, uri: &str, response: reqwest::Response, headers: HashMap<String, Vec<u8>>, expected: Option<Integrity>) -> Result<ApiStorage, Error> {
+        let mut downloads = self.downloads.lock().await;
+
+        if let Some(download) = downloads.get(uri) {
+            return Ok(download.storage());
+        }
+
+        // Created before the download is visible so readers never race its creation.
+        let file = File::create(self.entry_path(uri, ".partial")).await;
+
+        if let Err(error) = file {
+            println!("Could not create partial file for {uri}: {error}");
+            return Err(Error::Unknown());
+        }
+
+        let (download, progress) = Download::new(
+            self.entry_path(uri, ".partial"),
+            response.content_length(),
+            headers,
+            expected
+        );
+
+        downloads.insert(uri.to_string(), download.clone());
+        drop(downloads);
+
+        let me = self.clone();
+        let uri = uri.to_string();
+        let running = download.clone();
+
+        tokio::spawn(async move {
+            match running.run(file.unwrap(), response, &progress, &me.blobs).await {
+                Ok((written, integrity)) => {
+                    let entry = ApiStorage {
+                        headers: running.headers.clone(),
+                        body: vec![],
+                        stored_at: FreshnessPolicy::now(),
+                        stream: Some(me.blobs.stream(&integrity, written)),
+                        integrity: Some(integrity)
+                    };
+
+                    me.write_cache(uri.clone(), &entry).await;
+                    progress.send_replace(DownloadState::Done(written));
+                },
+                Err(error) => {
+                    println!("Download of {uri} failed: {error}");
+                    let _ = fs::remove_file(&running.partial).await;
+                    progress.send_replace(DownloadState::Failed);
+                }
+            }
+
+            me.downloads.lock().await.remove(&uri);
         });
 
-        return ApiInnerResult { result: Arc::new(RwLock::new(None)), fnc: func };
+        return Ok(download.storage());
     }
 
     async fn try_cache(&self, response: reqwest::StatusCode, uri: String, stored: &ApiStorage) {
-        if response.is_success() { 
+        if response.is_success() {
             self.do_cache(uri, stored).await;
         }
     }
 
-    async fn get_file_handle(&self, uri: String, options: &OpenOptions) -> Result<File, std::io::Error> {
+    fn entry_path(&self, uri: &str, extension: &str) -> PathBuf {
+        // Url safe, the standard alphabet contains `/` which would point into a subdirectory.
         let mut path = self.cache.clone();
-        path.push(BASE64_STANDARD.encode(uri) + ".bin");
-        return options.open(path).await;
+        path.push(BASE64_URL_SAFE.encode(uri) + extension);
+        return path;
     }
 
     async fn do_cache(&self, uri: String, stored: &ApiStorage) {
-        let result = serde_binary::to_vec(stored, serde_binary::binary_stream::Endian::Little).unwrap();
-        let options = OpenOptions::new().create(true).append(false).write(true).create_new(true).clone();
         let me = self.clone();
+        let stored = stored.clone();
         tokio::spawn(async move {
-            me.get_file_handle(uri, &options).await.unwrap().write_all(&result).await.unwrap();
+            me.write_cache(uri, &stored).await;
         });
     }
 
-    async fn do_load_cache(&self, uri: &String) -> Result<ApiStorage, ()> {
-        let file_handle = self.get_file_handle(uri.clone(), OpenOptions::new().create(false).append(false).write(false).create_new(false).read(true)).await;
+    async fn write_cache(&self, uri: String, stored: &ApiStorage) {
+        let value = entry::encode(stored);
+        let size = value.len() as u64;
+
+        if let Err(error) = self.store.store(&uri, value).await {
+            println!("Could not cache {uri}: {error}");
+            return;
+        }
+
+        let blob = stored.integrity.clone().zip(stored.stream.as_ref().and_then(|stream| stream.length()));
+        self.index.touch(&uri, size, blob).await;
+
+        if SearchIndex::indexes(&uri) && let Ok(packument) = serde_json::from_slice::<Value>(&stored.body) {
+            self.index_for_search(&uri, packument).await;
+        }
+    }
+
+    async fn do_load_cache(&self, uri: &str) -> Result<ApiStorage, ()> {
+        let value = match self.store.load(uri).await {
+            Ok(Some(value)) => value,
+            Ok(None) => return Err(()),
+            Err(error) => {
+                println!("Could not read cache entry for {uri}: {error}");
+                return Err(());
+            }
+        };
+
+        let mut stored = match entry::decode(&value) {
+            Ok(stored) => stored,
+            Err(error) => {
+                println!("Quarantining cache entry for {uri}: {error}");
+
+                if self.store.quarantine(uri, value).await.is_err() {
+                    let _ = self.store.delete(uri).await;
+                }
+
+                return Err(());
+            }
+        };
 
-        if file_handle.is_err() {
-            return Err(());
+        // A missing or damaged blob counts as a miss and is downloaded again. Blobs are hashed
+        // once per version of the file, not on every read.
+        if let Some(integrity) = &stored.integrity {
+            let length = self.blobs.verify(integrity).await.ok_or(())?;
+            stored.stream = Some(self.blobs.stream(integrity, length));
         }
 
-        let mut value = vec![];
-        file_handle.unwrap().read_to_end(&mut value).await.unwrap();
-        let result: ApiStorage = serde_binary::from_vec(value, serde_binary::binary_stream::Endian::Little).unwrap();
-        return Ok(result);
+        let blob = stored.integrity.clone().zip(stored.stream.as_ref().and_then(|stream| stream.length()));
+        self.index.touch(uri, value.len() as u64, blob).await;
+
+        return Ok(stored);
+    }
+
+    /// Removes temporary and partial files a previous process left behind in the local cache
+    /// directory when it died mid-write.
+    pub async fn remove_leftovers(&self) {
+        let Ok(mut dir) = fs::read_dir(&self.cache).await else {
+            return;
+        };
+
+        while let Ok(Some(file)) = dir.next_entry().await {
+            let name = file.file_name().to_string_lossy().to_string();
+
+            if name.contains(".tmp-") || name.ends_with(".partial") {
+                let _ = fs::remove_file(file.path()).await;
+            }
+        }
     }
 
 }
 
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn packument(latest: &str) -> Value {
+        return json!({
+            "dist-tags": { "latest": latest, "next": "3.0.0" },
+            "versions": { "1.0.0": {}, "1.1.0": {}, "2.0.0": {}, "3.0.0": {} },
+            "time": { "1.0.0": "", "1.1.0": "", "2.0.0": "", "3.0.0": "" }
+        });
+    }
+
+    #[test]
+    fn hidden_latest_moves_down_not_up() {
+        let mut hidden = packument("2.0.0");
+        ApiInner::hide_versions(&mut hidden, &["2.0.0".to_string()]);
+
+        assert_eq!(hidden["dist-tags"]["latest"], "1.1.0");
+        assert_eq!(hidden["dist-tags"]["next"], "3.0.0");
+        assert!(hidden["versions"].get("2.0.0").is_none());
+        assert!(hidden["time"].get("2.0.0").is_none());
+    }
+
+    #[test]
+    fn visible_latest_stays() {
+        let mut hidden = packument("2.0.0");
+        ApiInner::hide_versions(&mut hidden, &["3.0.0".to_string()]);
+
+        assert_eq!(hidden["dist-tags"]["latest"], "2.0.0");
+        assert!(hidden["dist-tags"].get("next").is_none());
+    }
+
+    #[test]
+    fn nothing_below_hidden_latest_leaves_no_latest() {
+        let mut hidden = packument("1.0.0");
+        ApiInner::hide_versions(&mut hidden, &["1.0.0".to_string()]);
+
+        assert!(hidden["dist-tags"].get("latest").is_none());
+    }
+
+    #[test]
+    fn rewrites_tarball_urls() {
+        let packument = json!({
+            "name": "lodash",
+            "versions": {
+                "4.17.21": { "dist": { "tarball": "https://registry.npmjs.org/lodash/-/lodash-4.17.21.tgz" } },
+                "4.17.20": { "dist": { "tarball": "https://mirror.example.com/lodash/-/lodash-4.17.20.tgz" } }
+            }
+        });
+
+        let modified = ApiInner::modified("https://registry.npmjs.org/".to_string(), "https://proxy.example.com/".to_string(), serde_json::to_vec(&packument).unwrap());
+        let modified: Value = serde_json::from_slice(&modified).unwrap();
+
+        assert_eq!(modified["versions"]["4.17.21"]["dist"]["tarball"], "https://proxy.example.com/lodash/-/lodash-4.17.21.tgz");
+        assert_eq!(modified["versions"]["4.17.20"]["dist"]["tarball"], "https://mirror.example.com/lodash/-/lodash-4.17.20.tgz");
+    }
+
+    #[test]
+    fn leaves_documents_without_versions() {
+        let error = json!({ "error": "Not found" });
+        let modified = ApiInner::modified("https://registry.npmjs.org/".to_string(), "https://proxy.example.com/".to_string(), serde_json::to_vec(&error).unwrap());
+
+        assert_eq!(serde_json::from_slice::<Value>(&modified).unwrap(), error);
+    }
+
+    #[test]
+    fn skips_versions_without_a_tarball() {
+        let packument = json!({
+            "versions": {
+                "1.0.0": { "dist": { "tarball": "https://registry.npmjs.org/a/-/a-1.0.0.tgz" } },
+                "0.2.0": {},
+                "0.1.0": { "dist": { "shasum": "00" } },
+                "0.0.1": "broken"
+            }
+        });
+
+        let modified = ApiInner::modified("https://registry.npmjs.org/".to_string(), "https://proxy.example.com/".to_string(), serde_json::to_vec(&packument).unwrap());
+        let modified: Value = serde_json::from_slice(&modified).unwrap();
+
+        assert_eq!(modified["versions"]["1.0.0"]["dist"]["tarball"], "https://proxy.example.com/a/-/a-1.0.0.tgz");
+        assert_eq!(modified["versions"]["0.2.0"], json!({}));
+        assert_eq!(modified["versions"]["0.0.1"], "broken");
+    }
+
+    #[test]
+    fn leaves_bodies_that_are_no_object() {
+        let modify = |body: &[u8]| ApiInner::modified("https://registry.npmjs.org/".to_string(), "https://proxy.example.com/".to_string(), body.to_vec());
+
+        assert_eq!(serde_json::from_slice::<Value>(&modify(b"[1, 2]")).unwrap(), json!([1, 2]));
+        assert_eq!(modify(b"not json"), b"not json");
+    }
+}
@@ -0,0 +1,188 @@
+use base64::{prelude::BASE64_STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Algorithm {
+    Sha1,
+    Sha256,
+    Sha512
+}
+
+impl Algorithm {
+    fn parse(name: &str) -> Option<Self> {
+        return match name {
+            "sha1" => Some(Algorithm::Sha1),
+            "sha256" => Some(Algorithm::Sha256),
+            "sha512" => Some(Algorithm::Sha512),
+            _ => None
+        };
+    }
+
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Algorithm::Sha1 => "sha1",
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Sha512 => "sha512"
+        };
+    }
+}
+
+/// Digest of a tarball, as published in a packument's `dist.integrity` or `dist.shasum`.
+/// Stored as its SRI string, serde_binary does not read back the unit variants it writes.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct Integrity {
+    pub algorithm: Algorithm,
+    pub digest: Vec<u8>,
+}
+
+impl Integrity {
+    /// Parses a subresource integrity string, picking the strongest supported hash.
+    pub fn from_sri(value: &str) -> Option<Self> {
+        return value.split_whitespace()
+            .filter_map(|entry| {
+                let (algorithm, digest) = entry.split_once('-')?;
+                let digest = digest.split('?').next()?;
+
+                return Some(Integrity {
+                    algorithm: Algorithm::parse(algorithm)?,
+                    digest: BASE64_STANDARD.decode(digest).ok()?
+                });
+            })
+            .max_by_key(|integrity| integrity.algorithm);
+    }
+
+    pub fn from_shasum(value: &str) -> Option<Self> {
+        return Some(Integrity {
+            algorithm: Algorithm::Sha1,
+            digest: hex::decode(value).ok()?
+        });
+    }
+
+    /// Reads the expected digest from a version's `dist` object, preferring `integrity` over the legacy `shasum`.
+    pub fn from_dist(dist: &Value) -> Option<Self> {
+        if let Some(integrity) = dist.get("integrity").and_then(|value| value.as_str()).and_then(Integrity::from_sri) {
+            return Some(integrity);
+        }
+
+        return dist.get("shasum").and_then(|value| value.as_str()).and_then(Integrity::from_shasum);
+    }
+
+    pub fn hex(&self) -> String {
+        return hex::encode(&self.digest);
+    }
+}
+
+impl std::fmt::Display for Integrity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "{}-{}", self.algorithm.name(), BASE64_STANDARD.encode(&self.digest));
+    }
+}
+
+impl From<Integrity> for String {
+    fn from(integrity: Integrity) -> Self {
+        return integrity.to_string();
+    }
+}
+
+impl TryFrom<String> for Integrity {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        return Integrity::from_sri(&value).ok_or(format!("invalid integrity {value}"));
+    }
+}
+
+
+pub enum Hasher {
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Sha512(Sha512)
+}
+
+impl Hasher {
+    pub fn new(algorithm: Algorithm) -> Self {
+        return match algorithm {
+            Algorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            Algorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            Algorithm::Sha512 => Hasher::Sha512(Sha512::new())
+        };
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Sha512(hasher) => hasher.update(data)
+        }
+    }
+
+    pub fn finish(self) -> Integrity {
+        return match self {
+            Hasher::Sha1(hasher) => Integrity { algorithm: Algorithm::Sha1, digest: hasher.finalize().to_vec() },
+            Hasher::Sha256(hasher) => Integrity { algorithm: Algorithm::Sha256, digest: hasher.finalize().to_vec() },
+            Hasher::Sha512(hasher) => Integrity { algorithm: Algorithm::Sha512, digest: hasher.finalize().to_vec() }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const EMPTY_SHA1: &str = "da39a3ee5e6b4b0d3255bfef95601890afd80709";
+    const EMPTY_SHA256: &str = "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=";
+
+    #[test]
+    fn picks_the_strongest_supported_hash() {
+        let sha512 = Hasher::new(Algorithm::Sha512).finish();
+        let sri = format!("md5-1B2M2Y8AsgTpgAmY7PhCfg== {EMPTY_SHA256} {sha512}?opt sha1-2jmj7l5rSw0yVb/vlWAYkK/YBwk=");
+
+        assert_eq!(Integrity::from_sri(&sri), Some(sha512));
+        assert_eq!(Integrity::from_sri(EMPTY_SHA256).unwrap().to_string(), EMPTY_SHA256);
+    }
+
+    #[test]
+    fn rejects_unusable_sri() {
+        assert_eq!(Integrity::from_sri(""), None);
+        assert_eq!(Integrity::from_sri("md5-1B2M2Y8AsgTpgAmY7PhCfg=="), None);
+        assert_eq!(Integrity::from_sri("sha512-not base64!"), None);
+        assert_eq!(Integrity::from_sri("sha512"), None);
+    }
+
+    #[test]
+    fn reads_dist() {
+        let shasum = Integrity::from_shasum(EMPTY_SHA1).unwrap();
+        assert_eq!(shasum, Hasher::new(Algorithm::Sha1).finish());
+        assert_eq!(shasum.hex(), EMPTY_SHA1);
+
+        assert_eq!(Integrity::from_dist(&json!({ "integrity": EMPTY_SHA256, "shasum": EMPTY_SHA1 })), Integrity::from_sri(EMPTY_SHA256));
+        assert_eq!(Integrity::from_dist(&json!({ "integrity": "md5-1B2M2Y8AsgTpgAmY7PhCfg==", "shasum": EMPTY_SHA1 })), Some(shasum));
+        assert_eq!(Integrity::from_dist(&json!({ "shasum": "not hex" })), None);
+    }
+
+    #[test]
+    fn compares_digests_and_algorithms() {
+        let mut hasher = Hasher::new(Algorithm::Sha256);
+        hasher.update(b"tarball");
+
+        assert_ne!(hasher.finish(), Integrity::from_sri(EMPTY_SHA256).unwrap());
+        assert_ne!(Hasher::new(Algorithm::Sha1).finish(), Hasher::new(Algorithm::Sha256).finish());
+        assert_eq!(Hasher::new(Algorithm::Sha256).finish(), Integrity::from_sri(EMPTY_SHA256).unwrap());
+    }
+
+    #[test]
+    fn serializes_as_sri() {
+        let integrity = Integrity::from_sri(EMPTY_SHA256).unwrap();
+
+        assert_eq!(serde_json::to_value(&integrity).unwrap(), json!(EMPTY_SHA256));
+        assert_eq!(serde_json::from_value::<Integrity>(json!(EMPTY_SHA256)).unwrap(), integrity);
+        assert!(serde_json::from_value::<Integrity>(json!("sha512")).is_err());
+    }
+}
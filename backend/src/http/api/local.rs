@@ -0,0 +1,285 @@
+use base64::{prelude::BASE64_STANDARD, Engine};
+use chrono::Utc;
+use serde_json::{Map, Value};
+
+use crate::config::Config;
+
+/// Published packuments live under this prefix in the cache store, next to entries for their
+/// tarballs so those are never evicted.
+pub const LOCAL_PREFIX: &str = "-/local/";
+
+
+/// Which side wins when a locally published package also exists upstream.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Precedence {
+    /// Local versions and dist-tags replace upstream ones of the same name.
+    Local,
+    /// Upstream versions and dist-tags win, local ones only fill the gaps.
+    Upstream,
+    /// Upstream is not asked at all for packages published here.
+    LocalOnly
+}
+
+impl Precedence {
+    pub fn new(config: &Config) -> Result<Self, String> {
+        return match config.local_precedence.as_str() {
+            "local" => Ok(Precedence::Local),
+            "upstream" => Ok(Precedence::Upstream),
+            "local-only" => Ok(Precedence::LocalOnly),
+            other => Err(format!("PROXY_LOCAL_PRECEDENCE {other} is not a precedence, expected local, upstream or local-only"))
+        };
+    }
+}
+
+pub fn key(uri: &str) -> String {
+    return LOCAL_PREFIX.to_string() + uri;
+}
+
+/// Merges the local packument into the upstream one, key by key in `versions`, `time` and `dist-tags`.
+pub fn merge(local: Value, upstream: Option<Value>, precedence: Precedence) -> Value {
+    let Some(mut merged) = upstream.filter(|upstream| upstream.get("versions").is_some_and(|versions| versions.is_object())) else {
+        return local;
+    };
+
+    for field in ["versions", "time", "dist-tags"] {
+        let Some(Value::Object(local_values)) = local.get(field) else {
+            continue;
+        };
+
+        let Some(target) = merged.as_object_mut().unwrap().entry(field).or_insert(Value::Object(Map::new())).as_object_mut() else {
+            continue;
+        };
+
+        for (key, value) in local_values {
+            if precedence != Precedence::Upstream || !target.contains_key(key) {
+                target.insert(key.clone(), value.clone());
+            }
+        }
+    }
+
+    return merged;
+}
+
+/// Why a publish was refused, with the status npm should see.
+pub struct Rejection(pub u16, pub String);
+
+/// A tarball of a publish, with the name it is served under.
+pub struct Attachment {
+    pub file_name: String,
+    pub data: Vec<u8>,
+    pub version: String,
+}
+
+/// Folds an `npm publish` payload into the existing local packument. Returns the new
+/// packument and the tarballs that still have to be stored, their `dist` already points at
+/// `tarball_base` (`<self url><package>/-/`).
+pub fn apply_publish(existing: Option<Value>, payload: &Value, package: &str, tarball_base: &str) -> Result<(Value, Vec<Attachment>), Rejection> {
+    if payload.get("name").and_then(|name| name.as_str()) != Some(package) {
+        return Err(Rejection(400, "package name in the body does not match the url".to_string()));
+    }
+
+    let Some(versions) = payload.get("versions").and_then(|versions| versions.as_object()) else {
+        return Err(Rejection(400, "no versions in the body".to_string()));
+    };
+
+    let attachments = payload.get("_attachments").and_then(|attachments| attachments.as_object()).cloned().unwrap_or_default();
+
+    let mut packument = existing.unwrap_or(serde_json::json!({ "_id": package, "name": package, "versions": {}, "time": {}, "dist-tags": {} }));
+    let now = Utc::now().to_rfc3339();
+    let mut stored: Vec<Attachment> = Vec::new();
+
+    for (version, manifest) in versions {
+        let known = packument["versions"].get(version).cloned();
+        // npm names every tarball of a publish `<name>-<version>.tgz`.
+        let name = format!("{package}-{version}.tgz");
+        let attachment = attachments.get(&name);
+
+        match (known, attachment) {
+            (Some(_), Some(_)) => return Err(Rejection(409, format!("cannot publish over the previously published version {version}"))),
+            (None, None) => return Err(Rejection(400, format!("no tarball attached for version {version}"))),
+            // Metadata only updates of a published version, e.g. `npm deprecate`. The tarball stays as it is.
+            (Some(known), None) => {
+                let mut manifest = manifest.clone();
+                manifest["dist"] = known["dist"].clone();
+                packument["versions"][version] = manifest;
+            },
+            (None, Some(attachment)) => {
+                let data = BASE64_STANDARD.decode(attachment["data"].as_str().unwrap_or_default())
+                    .map_err(|_| Rejection(400, format!("attachment {name} is not base64")))?;
+
+                let file_name = file_name_of(&name);
+                let mut manifest = manifest.clone();
+                manifest["dist"]["tarball"] = Value::String(tarball_base.to_string() + &file_name);
+                packument["versions"][version] = manifest;
+                packument["time"][version] = Value::String(now.clone());
+
+                stored.push(Attachment { file_name, data, version: version.clone() });
+            }
+        }
+    }
+
+    if let Some(Value::Object(tags)) = payload.get("dist-tags") {
+        for (tag, version) in tags {
+            packument["dist-tags"][tag] = version.clone();
+        }
+    }
+
+    for field in ["description", "readme", "maintainers", "repository", "homepage", "license", "keywords"] {
+        if let Some(value) = payload.get(field) {
+            packument[field] = value.clone();
+        }
+    }
+
+    if packument["time"].get("created").is_none() {
+        packument["time"]["created"] = Value::String(now.clone());
+    }
+
+    packument["time"]["modified"] = Value::String(now);
+
+    return Ok((packument, stored));
+}
+
+/// `@scope/name-1.0.0.tgz` is served as `name-1.0.0.tgz`, like npm does.
+fn file_name_of(attachment: &str) -> String {
+    return attachment.rsplit('/').next().unwrap_or(attachment).to_string();
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const BASE: &str = "https://proxy.example.com/@veto%2fcore/-/";
+
+    fn local() -> Value {
+        return json!({
+            "name": "@veto/core",
+            "versions": { "1.0.0": { "from": "local" }, "2.0.0": { "from": "local" } },
+            "time": { "1.0.0": "local", "2.0.0": "local" },
+            "dist-tags": { "latest": "2.0.0", "beta": "2.0.0" }
+        });
+    }
+
+    fn upstream() -> Value {
+        return json!({
+            "name": "@veto/core",
+            "versions": { "1.0.0": { "from": "upstream" }, "1.5.0": { "from": "upstream" } },
+            "time": { "1.0.0": "upstream", "1.5.0": "upstream" },
+            "dist-tags": { "latest": "1.5.0" }
+        });
+    }
+
+    fn payload(versions: &[&str], attached: &[&str]) -> Value {
+        let versions: Map<String, Value> = versions.iter().map(|version| (version.to_string(), json!({ "name": "@veto/core", "version": version, "dist": {} }))).collect();
+        let attachments: Map<String, Value> = attached.iter().map(|version| (format!("@veto/core-{version}.tgz"), json!({ "data": BASE64_STANDARD.encode(format!("tarball {version}")) }))).collect();
+
+        return json!({ "name": "@veto/core", "versions": versions, "_attachments": attachments, "dist-tags": { "latest": versions.keys().next_back() } });
+    }
+
+    fn accepted(result: Result<(Value, Vec<Attachment>), Rejection>) -> (Value, Vec<Attachment>) {
+        return match result {
+            Ok(published) => published,
+            Err(Rejection(status, message)) => panic!("the publish was rejected with {status}: {message}")
+        };
+    }
+
+    fn rejected(result: Result<(Value, Vec<Attachment>), Rejection>) -> (u16, String) {
+        return match result {
+            Err(Rejection(status, message)) => (status, message),
+            Ok(_) => panic!("the publish was accepted")
+        };
+    }
+
+    #[test]
+    fn upstream_wins_conflicts_and_local_fills_gaps() {
+        let merged = merge(local(), Some(upstream()), Precedence::Upstream);
+
+        assert_eq!(merged["versions"]["1.0.0"]["from"], "upstream");
+        assert_eq!(merged["versions"]["1.5.0"]["from"], "upstream");
+        assert_eq!(merged["versions"]["2.0.0"]["from"], "local");
+        assert_eq!(merged["time"]["1.0.0"], "upstream");
+        assert_eq!(merged["dist-tags"], json!({ "latest": "1.5.0", "beta": "2.0.0" }));
+    }
+
+    #[test]
+    fn local_wins_conflicts() {
+        let merged = merge(local(), Some(upstream()), Precedence::Local);
+
+        assert_eq!(merged["versions"]["1.0.0"]["from"], "local");
+        assert_eq!(merged["versions"]["1.5.0"]["from"], "upstream");
+        assert_eq!(merged["dist-tags"], json!({ "latest": "2.0.0", "beta": "2.0.0" }));
+    }
+
+    #[test]
+    fn local_only_packages_stay_as_they_are() {
+        assert_eq!(merge(local(), None, Precedence::Upstream), local());
+        assert_eq!(merge(local(), Some(json!({ "error": "Not found" })), Precedence::Upstream), local());
+    }
+
+    #[test]
+    fn publishes_scoped_packages() {
+        let (packument, attachments) = accepted(apply_publish(None, &payload(&["1.0.0"], &["1.0.0"]), "@veto/core", BASE));
+
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(attachments[0].file_name, "core-1.0.0.tgz");
+        assert_eq!(attachments[0].version, "1.0.0");
+        assert_eq!(attachments[0].data, b"tarball 1.0.0");
+        assert_eq!(packument["versions"]["1.0.0"]["dist"]["tarball"], BASE.to_string() + "core-1.0.0.tgz");
+        assert_eq!(packument["dist-tags"]["latest"], "1.0.0");
+        assert!(packument["time"]["1.0.0"].is_string());
+        assert!(packument["time"]["created"].is_string());
+    }
+
+    #[test]
+    fn adds_versions_and_keeps_their_history() {
+        let (existing, _) = accepted(apply_publish(None, &payload(&["1.0.0"], &["1.0.0"]), "@veto/core", BASE));
+        let created = existing["time"]["created"].clone();
+
+        let (packument, attachments) = accepted(apply_publish(Some(existing), &payload(&["1.1.0"], &["1.1.0"]), "@veto/core", BASE));
+
+        assert_eq!(attachments.len(), 1);
+        assert!(packument["versions"].get("1.0.0").is_some());
+        assert!(packument["versions"].get("1.1.0").is_some());
+        assert_eq!(packument["time"]["created"], created);
+        assert_eq!(packument["dist-tags"]["latest"], "1.1.0");
+    }
+
+    #[test]
+    fn refuses_to_publish_over_a_version() {
+        let (existing, _) = accepted(apply_publish(None, &payload(&["1.0.0"], &["1.0.0"]), "@veto/core", BASE));
+
+        assert_eq!(rejected(apply_publish(Some(existing), &payload(&["1.0.0"], &["1.0.0"]), "@veto/core", BASE)).0, 409);
+    }
+
+    #[test]
+    fn updates_metadata_without_touching_the_tarball() {
+        let (existing, _) = accepted(apply_publish(None, &payload(&["1.0.0"], &["1.0.0"]), "@veto/core", BASE));
+        let mut deprecate = payload(&["1.0.0"], &[]);
+        deprecate["versions"]["1.0.0"]["deprecated"] = json!("use 2.x");
+        deprecate["versions"]["1.0.0"]["dist"] = json!({ "tarball": "https://elsewhere.example.com/core-1.0.0.tgz" });
+
+        let (packument, attachments) = accepted(apply_publish(Some(existing.clone()), &deprecate, "@veto/core", BASE));
+
+        assert!(attachments.is_empty());
+        assert_eq!(packument["versions"]["1.0.0"]["deprecated"], "use 2.x");
+        assert_eq!(packument["versions"]["1.0.0"]["dist"], existing["versions"]["1.0.0"]["dist"]);
+    }
+
+    #[test]
+    fn refuses_broken_publishes() {
+        assert_eq!(rejected(apply_publish(None, &payload(&["1.0.0"], &["1.0.0"]), "@veto/other", BASE)).0, 400);
+        assert_eq!(rejected(apply_publish(None, &json!({ "name": "@veto/core" }), "@veto/core", BASE)).0, 400);
+        assert_eq!(rejected(apply_publish(None, &payload(&["1.0.0"], &[]), "@veto/core", BASE)).1, "no tarball attached for version 1.0.0");
+
+        let mut garbled = payload(&["1.0.0"], &["1.0.0"]);
+        garbled["_attachments"]["@veto/core-1.0.0.tgz"]["data"] = json!("not base64!");
+        assert_eq!(rejected(apply_publish(None, &garbled, "@veto/core", BASE)).0, 400);
+    }
+
+    #[test]
+    fn names_tarballs_without_their_scope() {
+        assert_eq!(file_name_of("@veto/core-1.0.0.tgz"), "core-1.0.0.tgz");
+        assert_eq!(file_name_of("lodash-4.17.21.tgz"), "lodash-4.17.21.tgz");
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::BTreeSet;
+
+use serde_json::Value;
+
+
+/// A package version a lockfile resolves to.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Locked {
+    pub name: String,
+    pub version: String,
+}
+
+/// Reads the registry packages out of a `package-lock.json` (v2/v3), `pnpm-lock.yaml` or
+/// `yarn.lock` (classic and berry). Workspace, git and file dependencies are skipped, there
+/// is nothing to fetch for them.
+pub fn parse(content: &str) -> Result<Vec<Locked>, String> {
+    let trimmed = content.trim_start();
+
+    let locked = if trimmed.starts_with('{') {
+        package_lock(content)?
+    } else if content.contains("# yarn lockfile v1") || content.contains("\n__metadata:") {
+        yarn_lock(content)
+    } else if trimmed.starts_with("lockfileVersion:") || content.contains("\nlockfileVersion:") {
+        pnpm_lock(content)?
+    } else {
+        return Err("not a package-lock.json, pnpm-lock.yaml or yarn.lock".to_string());
+    };
+
+    return Ok(locked.into_iter().collect());
+}
+
+fn package_lock(content: &str) -> Result<BTreeSet<Locked>, String> {
+    let lock: Value = serde_json::from_str(content).map_err(|error| format!("package-lock.json does not parse: {error}"))?;
+
+    let Some(packages) = lock.get("packages").and_then(|packages| packages.as_object()) else {
+        return Err("only lockfileVersion 2 and 3 are supported, there is no packages object".to_string());
+    };
+
+    let mut locked = BTreeSet::new();
+
+    for (path, package) in packages {
+        // The root project and workspace links.
+        let Some((_, name)) = path.rsplit_once("node_modules/") else {
+            continue;
+        };
+
+        if package.get("link").and_then(|link| link.as_bool()).unwrap_or(false) {
+            continue;
+        }
+
+        let resolved = package.get("resolved").and_then(|resolved| resolved.as_str()).unwrap_or_default();
+
+        if !resolved.is_empty() && !resolved.starts_with("http") {
+            continue;
+        }
+
+        let Some(version) = package.get("version").and_then(|version| version.as_str()) else {
+            continue;
+        };
+
+        // Aliases (`"x": "npm:y@1.0.0"`) carry the real name.
+        let name = package.get("name").and_then(|name| name.as_str()).unwrap_or(name);
+        locked.insert(Locked { name: name.to_string(), version: version.to_string() });
+    }
+
+    return Ok(locked);
+}
+
+fn pnpm_lock(content: &str) -> Result<BTreeSet<Locked>, String> {
+    let lock: serde_yaml::Value = serde_yaml::from_str(content).map_err(|error| format!("pnpm-lock.yaml does not parse: {error}"))?;
+    let mut locked = BTreeSet::new();
+
+    let Some(packages) = lock.get("packages").and_then(|packages| packages.as_mapping()) else {
+        return Ok(locked);
+    };
+
+    for (key, package) in packages {
+        let Some(key) = key.as_str() else {
+            continue;
+        };
+
+        // Git and tarball dependencies resolve to something other than the registry.
+        if package.get("resolution").and_then(|resolution| resolution.get("tarball").or(resolution.get("repo"))).is_some() {
+            continue;
+        }
+
+        // `/name@1.0.0(peer@2.0.0)` (v6), `name@1.0.0` (v9) or `/name/1.0.0` (v5).
+        let key = key.trim_start_matches('/');
+        let key = key.split_once('(').map(|(key, _)| key).unwrap_or(key);
+
+        let split = match key.get(1..).and_then(|rest| rest.rfind('@')) {
+            Some(index) => Some((&key[..index + 1], &key[index + 2..])),
+            None => key.rsplit_once('/')
+        };
+
+        if let Some((name, version)) = split && !name.is_empty() && !version.contains(':') {
+            locked.insert(Locked { name: name.to_string(), version: version.to_string() });
+        }
+    }
+
+    return Ok(locked);
+}
+
+fn yarn_lock(content: &str) -> BTreeSet<Locked> {
+    let mut locked = BTreeSet::new();
+    let mut name: Option<String> = None;
+
+    for line in content.lines() {
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+
+        if !line.starts_with(' ') {
+            // `"@scope/name@^1.0.0", "@scope/name@npm:^1.1.0":`, the first spec names the package.
+            let spec = line.trim_end_matches(':').split(", ").next().unwrap_or_default().trim_matches('"');
+            name = yarn_spec_name(spec);
+            continue;
+        }
+
+        let setting = line.trim();
+        let version = setting.strip_prefix("version ").or(setting.strip_prefix("version: "));
+
+        if let Some(version) = version && let Some(package) = name.take() {
+            locked.insert(Locked { name: package, version: version.trim_matches('"').to_string() });
+        }
+    }
+
+    return locked;
+}
+
+/// The registry package a yarn spec points at, `None` for workspaces, patches, git and files.
+fn yarn_spec_name(spec: &str) -> Option<String> {
+    if spec == "__metadata" {
+        return None;
+    }
+
+    let index = spec.get(1..)?.find('@')? + 1;
+    let (name, range) = (&spec[..index], &spec[index + 1..]);
+
+    let Some((protocol, rest)) = range.split_once(':') else {
+        return Some(name.to_string());
+    };
+
+    if protocol != "npm" {
+        return None;
+    }
+
+    // `alias@npm:real@^1.0.0`
+    if let Some(at) = rest.get(1..).and_then(|rest| rest.find('@')) {
+        return Some(rest[..at + 1].to_string());
+    }
+
+    return Some(name.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locked(pairs: &[(&str, &str)]) -> Vec<Locked> {
+        return pairs.iter().map(|(name, version)| Locked { name: name.to_string(), version: version.to_string() }).collect();
+    }
+
+    #[test]
+    fn rejects_package_lock_v1() {
+        let lock = r#"{
+            "lockfileVersion": 1,
+            "dependencies": { "lodash": { "version": "4.17.21", "resolved": "https://registry.npmjs.org/lodash/-/lodash-4.17.21.tgz" } }
+        }"#;
+
+        assert!(parse(lock).unwrap_err().contains("lockfileVersion 2 and 3"));
+    }
+
+    #[test]
+    fn reads_package_lock_v2_and_v3() {
+        for version in [2, 3] {
+            let lock = format!(r#"{{
+                "lockfileVersion": {version},
+                "packages": {{
+                    "": {{ "name": "app", "version": "1.0.0" }},
+                    "node_modules/lodash": {{ "version": "4.17.21", "resolved": "https://registry.npmjs.org/lodash/-/lodash-4.17.21.tgz" }},
+                    "node_modules/@veto/core": {{ "version": "2.0.0" }},
+                    "node_modules/@veto/core/node_modules/lodash": {{ "version": "3.10.1", "resolved": "https://registry.npmjs.org/lodash/-/lodash-3.10.1.tgz" }},
+                    "node_modules/old": {{ "name": "new", "version": "1.2.3", "resolved": "https://registry.npmjs.org/new/-/new-1.2.3.tgz" }},
+                    "node_modules/workspace": {{ "resolved": "packages/workspace", "link": true }},
+                    "node_modules/forked": {{ "version": "1.0.0", "resolved": "git+ssh://git@github.com/veto/forked.git#abc" }},
+                    "node_modules/local": {{ "version": "1.0.0", "resolved": "file:../local" }}
+                }}
+            }}"#);
+
+            assert_eq!(parse(&lock).unwrap(), locked(&[("@veto/core", "2.0.0"), ("lodash", "3.10.1"), ("lodash", "4.17.21"), ("new", "1.2.3")]));
+        }
+
+        assert!(parse("{ not json").unwrap_err().contains("does not parse"));
+    }
+
+    #[test]
+    fn reads_pnpm_lock() {
+        let lock = "lockfileVersion: '9.0'\n\npackages:\n\n  lodash@4.17.21:\n    resolution: {integrity: sha512-x}\n\n  '@veto/core@2.0.0(react@18.0.0)':\n    resolution: {integrity: sha512-y}\n\n  /old/1.0.0:\n    resolution: {integrity: sha512-z}\n\n  forked@https://codeload.github.com/veto/forked/tar.gz/abc:\n    resolution: {tarball: https://codeload.github.com/veto/forked/tar.gz/abc}\n";
+
+        assert_eq!(parse(lock).unwrap(), locked(&[("@veto/core", "2.0.0"), ("lodash", "4.17.21"), ("old", "1.0.0")]));
+    }
+
+    #[test]
+    fn reads_yarn_lock() {
+        let classic = "# yarn lockfile v1\n\n\n\"@veto/core@^2.0.0\", \"@veto/core@^2.1.0\":\n  version \"2.1.0\"\n  resolved \"https://registry.yarnpkg.com/@veto/core/-/core-2.1.0.tgz\"\n\nlodash@^4.17.0:\n  version \"4.17.21\"\n";
+        assert_eq!(parse(classic).unwrap(), locked(&[("@veto/core", "2.1.0"), ("lodash", "4.17.21")]));
+
+        let berry = "# This file is generated by running \"yarn install\" inside your project.\n\n__metadata:\n  version: 6\n\n\"alias@npm:lodash@^4.17.0\":\n  version: 4.17.21\n\n\"app@workspace:.\":\n  version: 0.0.0-use.local\n\n\"forked@git+ssh://git@github.com/veto/forked.git\":\n  version: 1.0.0\n";
+        assert_eq!(parse(berry).unwrap(), locked(&[("lodash", "4.17.21")]));
+    }
+
+    #[test]
+    fn rejects_unknown_formats() {
+        assert!(parse("hello").is_err());
+    }
+}
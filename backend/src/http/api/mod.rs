@@ -1,68 +1,192 @@
-use std::{collections::HashMap, path, sync::Arc};
+use std::{collections::{HashMap, HashSet}, path, sync::Arc};
 
-use axum::{extract::{Path, State}, routing::{delete, get}, Json, Router};
-use serde_json::json;
-use tokio::sync::RwLock;
+use axum::{body::Bytes, extract::{DefaultBodyLimit, Path, Query, State}, http::{header::{ACCEPT, ACCEPT_ENCODING, CONTENT_ENCODING, VARY}, HeaderMap, StatusCode}, routing::{delete, get, post, put}, Json, Router};
+use serde_json::{json, Value};
+use tokio::sync::{Mutex, RwLock};
 
-use crate::{config::Config, http::api::{api::Api, inner::ApiInner}};
+use crate::{config::Config, domain::Tokens::Scope, http::{auth::scope, api::{api::Api, audit::{Audit, Endpoint}, error::Error, blobs::BlobStore, compression::{Compression, Encoding}, credentials::Credentials, eviction::CacheIndex, freshness::FreshnessPolicy, inner::ApiInner, local::Precedence, offline::Offline, policy::Policy, prewarm::Prewarm, quarantine::Quarantine, search::SearchIndex, tags::DistTags, upstream::Upstreams}}};
 
+mod abbreviated;
+#[allow(clippy::module_inception)]
 mod api;
+mod audit;
+mod blobs;
+mod compression;
+mod credentials;
+mod download;
+mod entry;
+mod eviction;
 mod inner;
 mod error;
+mod freshness;
+mod integrity;
+mod local;
+mod policy;
+mod lockfile;
+mod offline;
+mod prewarm;
+mod quarantine;
+mod range;
+mod search;
 mod storage;
+mod store;
+mod tags;
+mod upstream;
+
+/// Publishes carry their tarballs base64 encoded in the body.
+const PUBLISH_BODY_LIMIT: usize = 128 * 1024 * 1024;
+
+
+/// The registry answers at most this many search results per page.
+const SEARCH_MAX_SIZE: usize = 250;
+
+/// Pages start at most this far in, every earlier result is ranked to cut a page.
+const SEARCH_MAX_FROM: usize = 10000;
+
+
+/// Lockfiles of large monorepos easily exceed axum's default limit, quick audits carry one too.
+const LOCKFILE_BODY_LIMIT: usize = 64 * 1024 * 1024;
 
 
 #[derive(Clone)]
 struct ApiState {
-    api: Api
+    api: Api,
+    prewarm: Prewarm
 }
 
-pub fn api_routes(router: Router, config: &Config) -> Router {
+pub fn api_routes(router: Router, config: &Config) -> Result<Router, String> {
 
-    let cache = path::absolute("./cache/".to_string()).unwrap();
+    let cache = path::absolute("./cache/").unwrap();
+
+    let store = store::from_config(config, &cache)?;
+    let blobs = BlobStore::new(store.clone());
 
     let api = Api {
         api_inner: Box::new(ApiInner { 
-            registry_uri: config.registry_url.clone(),
+            upstreams: Upstreams::new(config),
+            credentials: Credentials::new(config),
             resulting_registry_uri: config.self_url.clone(),
-            cache: cache
+            freshness: FreshnessPolicy::new(config),
+            refreshing: Arc::new(Mutex::new(HashSet::new())),
+            downloads: Arc::new(Mutex::new(HashMap::new())),
+            index: CacheIndex::new(config, store.clone(), blobs.clone()),
+            blobs,
+            precedence: Precedence::new(config)?,
+            publish_over_upstream: config.publish_over_upstream.clone(),
+            publishing: Arc::new(Mutex::new(())),
+            tags: DistTags::new(config, store.clone()),
+            policy: Policy::new(config)?,
+            quarantine: Quarantine::new(config, store.clone()),
+            audit: Audit::new(config),
+            offline: Offline::new(config),
+            search: SearchIndex::new(),
+            search_upstream: config.search_upstream,
+            compression: Compression::new(config),
+            compressing: Arc::new(Mutex::new(HashSet::new())),
+            store,
+            cache
         }),
         // stored_responses: Arc::new(RwLock::new(HashMap::new())),
         running_requests: Arc::new(RwLock::new(HashMap::new()))
     };
 
+    {
+        let inner = api.api_inner.clone();
+        tokio::spawn(async move {
+            inner.remove_leftovers().await;
+            inner.rebuild_search().await;
+        });
+    }
+
     let api_state = ApiState { 
-        api: api
+        api,
+        prewarm: Prewarm::new(config)
     };
 
 
-    router
+    return Ok(router
         .route("/-/package/{package_name}/dist-tags", get(   
             |Path(package_name): Path<String>, State(mut api): State<ApiState>| async move {
                 api.api.get_dist_tags(package_name).await
             }
-        ).with_state(api_state.clone()))
+        ).with_state(api_state.clone()).route_layer(scope::require(Scope::Read)))
+        .route("/-/package/{package_name}/dist-tags/{tag}", put(
+            |Path((package_name, tag)): Path<(String, String)>, State(api): State<ApiState>, Json(version): Json<String>| async move {
+                api.api.set_dist_tag(package_name, tag, Some(version)).await
+            }
+        ).delete(
+            |Path((package_name, tag)): Path<(String, String)>, State(api): State<ApiState>| async move {
+                api.api.set_dist_tag(package_name, tag, None).await
+            }
+        ).with_state(api_state.clone()).route_layer(scope::require(Scope::Publish)))
+        .route("/-/v1/search", get(|Query(query): Query<HashMap<String, String>>, State(api): State<ApiState>| async move {
+            let number = |key: &str, default: usize| query.get(key).and_then(|value| value.parse().ok()).unwrap_or(default);
+            let text = query.get("text").cloned().unwrap_or_default();
+
+            return Json(api.api.search(text, number("from", 0).min(SEARCH_MAX_FROM), number("size", 20).clamp(1, SEARCH_MAX_SIZE)).await);
+        }).with_state(api_state.clone()).route_layer(scope::require(Scope::Read)))
+        .route("/-/npm/v1/security/advisories/bulk", post(|State(api): State<ApiState>, headers: HeaderMap, body: Bytes| async move {
+            return api.api.audit(Endpoint::Bulk, headers.get(CONTENT_ENCODING).and_then(|encoding| encoding.to_str().ok()), body.to_vec()).await;
+        }).layer(DefaultBodyLimit::max(LOCKFILE_BODY_LIMIT)).with_state(api_state.clone()).route_layer(scope::require(Scope::Read)))
+        .route("/-/npm/v1/security/audits/quick", post(|State(api): State<ApiState>, headers: HeaderMap, body: Bytes| async move {
+            return api.api.audit(Endpoint::Quick, headers.get(CONTENT_ENCODING).and_then(|encoding| encoding.to_str().ok()), body.to_vec()).await;
+        }).layer(DefaultBodyLimit::max(LOCKFILE_BODY_LIMIT)).with_state(api_state.clone()).route_layer(scope::require(Scope::Read)))
         .route("/-/api/all", get(|State(api): State<ApiState>| async move {
                 Json(json!(api.api.get_cached_packages().await))
             }
-        ).with_state(api_state.clone()))
+        ).with_state(api_state.clone()).route_layer(scope::require(Scope::Read)))
+        .route("/-/api/prewarm", post(|State(api): State<ApiState>, lockfile: String| async move {
+            return match api.prewarm.start(api.api.clone(), &lockfile).await {
+                Ok(id) => Ok((StatusCode::ACCEPTED, Json(json!({ "id": id })))),
+                Err(message) => Err(Error::Status(400, message))
+            };
+        }).layer(DefaultBodyLimit::max(LOCKFILE_BODY_LIMIT)).with_state(api_state.clone()).route_layer(scope::require(Scope::Publish)))
+        .route("/-/api/prewarm/{id}", get(|Path(id): Path<String>, State(api): State<ApiState>| async move {
+            return api.prewarm.progress(&id).await.map(Json).ok_or(Error::Status(404, "no such prewarm job".to_string()));
+        }).with_state(api_state.clone()).route_layer(scope::require(Scope::Read)))
+        .route("/-/api/offline/misses", get(|State(api): State<ApiState>| async move {
+            return Json(api.api.offline_misses().await);
+        }).with_state(api_state.clone()).route_layer(scope::require(Scope::Read)))
+        .route("/-/api/offline/misses", delete(|State(api): State<ApiState>| async move {
+            api.api.clear_offline_misses().await;
+            return Json(json!({ "ok": true }));
+        }).with_state(api_state.clone()).route_layer(scope::require(Scope::Admin)))
+        .route("/-/api/quarantine/{package_name}/{version}/release", post(|Path((package_name, version)): Path<(String, String)>, State(api): State<ApiState>| async move {
+            return api.api.release_quarantined(package_name, version).await.map(|_| Json(json!({ "ok": true })));
+        }).with_state(api_state.clone()).route_layer(scope::require(Scope::Admin)))
         .route("/-/api/delete/{package_name}", delete(|Path(package_name): Path<String>, State(api): State<ApiState>| async move {
-            print!("{package_name}");
             api.api.delete_cached_file(package_name).await;
             return Json("{}");
-        }).with_state(api_state.clone()))
+        }).with_state(api_state.clone()).route_layer(scope::require(Scope::Admin)))
         .route("/{package_name}/-/{file_name}", get(
-            |Path((package_name, file_name)): Path<(String, String)>, State(mut api): State<ApiState>| async move {
-                api.api.get_file(package_name, file_name).await
-        }).with_state(api_state.clone()))
+            |Path((package_name, file_name)): Path<(String, String)>, State(mut api): State<ApiState>, headers: HeaderMap| async move {
+                api.api.get_file(package_name, file_name).await.map(|stored| range::respond(stored, &headers))
+        }).with_state(api_state.clone()).route_layer(scope::require(Scope::Read)))
+        // GitLab names the tarballs of scoped packages `@scope/name-1.0.0.tgz`.
+        .route("/@{package_namespace}/{package_name}/-/@{file_namespace}/{file_name}", get(
+            |Path((package_namespace, package_name, file_namespace, file_name)): Path<(String, String, String, String)>, State(mut api): State<ApiState>, headers: HeaderMap| async move {
+                api.api.get_file("@".to_string() + &package_namespace + "/" + &package_name, "@".to_string() + &file_namespace + "/" + &file_name).await
+                    .map(|stored| range::respond(stored, &headers))
+            }
+        ).with_state(api_state.clone()).route_layer(scope::require(Scope::Read)))
         .route("/@{package_namespace}/{package_name}/-/{file_name}", get(
-            |Path((package_namespace, package_name, file_name)): Path<(String, String, String)>, State(mut api): State<ApiState>| async move {
+            |Path((package_namespace, package_name, file_name)): Path<(String, String, String)>, State(mut api): State<ApiState>, headers: HeaderMap| async move {
                 api.api.get_file("@".to_string() + &package_namespace + "/" + &package_name, file_name).await
+                    .map(|stored| range::respond(stored, &headers))
             }
-        ).with_state(api_state.clone()))
+        ).with_state(api_state.clone()).route_layer(scope::require(Scope::Read)))
         .route("/{package_name}", get(
-            |Path(package_name): Path<String>, State(mut api): State<ApiState>| async move {
-                api.api.get_package_metadata(package_name).await
+            |Path(package_name): Path<String>, State(mut api): State<ApiState>, headers: HeaderMap| async move {
+                let abbreviated = abbreviated::is_requested(headers.get(ACCEPT).and_then(|accept| accept.to_str().ok()));
+                let encoding = Encoding::negotiate(headers.get(ACCEPT_ENCODING).and_then(|accept| accept.to_str().ok()));
+
+                return api.api.serve_package_metadata(package_name, abbreviated, encoding).await
+                    .map(|stored| ([(VARY, "accept, accept-encoding")], stored));
+            }
+        ).with_state(api_state.clone()).route_layer(scope::require(Scope::Read)))
+        .route("/{package_name}", put(
+            |Path(package_name): Path<String>, State(api): State<ApiState>, Json(payload): Json<Value>| async move {
+                api.api.publish(package_name, payload).await.map(|_| Json(json!({ "ok": true })))
             }
-        ).with_state(api_state.clone()))
+        ).layer(DefaultBodyLimit::max(PUBLISH_BODY_LIMIT)).with_state(api_state.clone()).route_layer(scope::require(Scope::Publish))));
 }
\ No newline at end of file
@@ -0,0 +1,64 @@
+use std::{collections::HashMap, sync::Arc};
+
+use chrono::Utc;
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::{config::Config, http::api::{error::Error, upstream::Upstreams}};
+
+
+/// Something a client asked for while offline that is not in the cache.
+#[derive(Serialize, Clone)]
+pub struct Miss {
+    pub package: String,
+    /// The tarball, packuments have none.
+    pub file: Option<String>,
+    pub count: u64,
+    pub first: String,
+    pub last: String,
+}
+
+/// With `PROXY_OFFLINE` nothing is fetched from upstream. Cached entries are served however
+/// old they are, anything else is a 404 and remembered as a miss, so the cache can be seeded
+/// with exactly what a build needed.
+#[derive(Clone)]
+pub struct Offline {
+    pub enabled: bool,
+    /// Misses of this replica by cache uri.
+    misses: Arc<Mutex<HashMap<String, Miss>>>,
+}
+
+impl Offline {
+    pub fn new(config: &Config) -> Self {
+        return Self {
+            enabled: config.offline,
+            misses: Arc::new(Mutex::new(HashMap::new()))
+        };
+    }
+
+    /// Records that `uri` is not cached and returns the error to answer with.
+    pub async fn miss(&self, uri: &str) -> Error {
+        let now = Utc::now().to_rfc3339();
+        let package = Upstreams::package_of(uri);
+        let file = uri.split_once("/-/").and_then(|(_, file)| urlencoding::decode(file).ok()).map(|file| file.to_string());
+
+        println!("Offline, {uri} is not cached");
+
+        let mut misses = self.misses.lock().await;
+        let miss = misses.entry(uri.to_string()).or_insert_with(|| Miss { package: package.clone(), file, count: 0, first: now.clone(), last: now.clone() });
+        miss.count += 1;
+        miss.last = now;
+
+        return Error::Status(404, format!("Not found, {package} is not cached and the proxy is offline"));
+    }
+
+    pub async fn misses(&self) -> Vec<Miss> {
+        let mut misses: Vec<Miss> = self.misses.lock().await.values().cloned().collect();
+        misses.sort_by(|a, b| a.package.cmp(&b.package).then(a.file.cmp(&b.file)));
+        return misses;
+    }
+
+    pub async fn clear(&self) {
+        self.misses.lock().await.clear();
+    }
+}
@@ -0,0 +1,349 @@
+use std::{path::PathBuf, sync::Arc, time::{Duration, SystemTime}};
+
+use semver::{Version, VersionReq};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::{fs, sync::RwLock};
+
+use crate::{config::Config, http::api::upstream::Upstreams};
+
+
+#[derive(Deserialize, Default)]
+struct PolicyFile {
+    /// Package patterns, when given every package matching none of them is denied.
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<DenyRule>,
+}
+
+#[derive(Deserialize)]
+struct DenyRule {
+    /// Package pattern, `*` stands for any run of characters.
+    package: String,
+    /// npm semver range, only these versions are denied. The whole package is when missing.
+    versions: Option<String>,
+    reason: Option<String>,
+}
+
+struct Rule {
+    package: String,
+    versions: Option<(String, Vec<VersionReq>)>,
+    reason: Option<String>,
+}
+
+impl Rule {
+    fn describe(&self) -> String {
+        let mut description = format!("denied by rule `{}", self.package);
+
+        if let Some((range, _)) = &self.versions {
+            description += &format!("@{range}");
+        }
+
+        description += "`";
+
+        if let Some(reason) = &self.reason {
+            description += &format!(" ({reason})");
+        }
+
+        return description;
+    }
+
+    fn denies_version(&self, version: &str) -> bool {
+        let Some((_, ranges)) = &self.versions else {
+            return true;
+        };
+
+        let Ok(version) = Version::parse(version.trim_start_matches('v')) else {
+            return false;
+        };
+
+        return ranges.iter().any(|range| range.matches(&version));
+    }
+}
+
+#[derive(Default)]
+struct Rules {
+    allow: Vec<String>,
+    deny: Vec<Rule>,
+}
+
+/// Which packages and versions may be served, from a yaml (or json) file like:
+///
+/// ```text
+/// allow:
+///   - "@veto/*"
+///   - "react*"
+/// deny:
+///   - package: "event-stream"
+///     versions: "3.3.6"
+///     reason: "flatmap-stream backdoor"
+///   - package: "*-malware"
+/// ```
+///
+/// Denied packages are refused with a 403 before anything is fetched, denied versions are
+/// left out of packuments and their tarballs refused. The file is re-read whenever it changes.
+pub struct Policy {
+    path: Option<PathBuf>,
+    rules: RwLock<Rules>,
+    modified: RwLock<Option<SystemTime>>,
+}
+
+impl Policy {
+    /// The file is read before anything is served, a missing or broken policy stops startup
+    /// instead of letting everything through.
+    pub fn new(config: &Config) -> Result<Arc<Self>, String> {
+        let path = config.policy.clone().map(PathBuf::from);
+        let mut rules = Rules::default();
+        let mut modified = None;
+
+        if let Some(path) = &path {
+            let unreadable = |error: std::io::Error| format!("PROXY_POLICY {} cannot be read: {error}", path.display());
+            modified = Some(std::fs::metadata(path).and_then(|metadata| metadata.modified()).map_err(unreadable)?);
+            rules = Policy::parse(&std::fs::read_to_string(path).map_err(unreadable)?).map_err(|error| format!("PROXY_POLICY {} {error}", path.display()))?;
+            println!("Loaded policy with {} allowed patterns and {} deny rules", rules.allow.len(), rules.deny.len());
+        }
+
+        let element = Arc::new(Self {
+            path,
+            rules: RwLock::new(rules),
+            modified: RwLock::new(modified)
+        });
+
+        if element.path.is_none() {
+            return Ok(element);
+        }
+
+        let element_clone = Arc::clone(&element);
+        let reload_interval = Duration::from_secs(config.policy_reload);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(reload_interval);
+            loop {
+                interval.tick().await;
+                element_clone.reload().await;
+            }
+        });
+
+        return Ok(element);
+    }
+
+    /// Explains why `package` (or the given version of it) may not be served.
+    pub async fn check(&self, package: &str, version: Option<&str>) -> Result<(), String> {
+        let rules = self.rules.read().await;
+
+        if !rules.allow.is_empty() && !rules.allow.iter().any(|pattern| Upstreams::matches(pattern, package)) {
+            return Err(format!("{package} is blocked by policy, it matches no allowed pattern ({})", rules.allow.join(", ")));
+        }
+
+        for rule in rules.deny.iter().filter(|rule| Upstreams::matches(&rule.package, package)) {
+            match (&rule.versions, version) {
+                (None, _) => return Err(format!("{package} is blocked by policy, {}", rule.describe())),
+                (Some(_), Some(version)) if rule.denies_version(version) => {
+                    return Err(format!("{package}@{version} is blocked by policy, {}", rule.describe()));
+                },
+                _ => {}
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Versions of the packument a rule denies.
+    pub async fn denied_versions(&self, package: &str, packument: &Value) -> Vec<String> {
+        let rules = self.rules.read().await;
+        let matching: Vec<&Rule> = rules.deny.iter().filter(|rule| rule.versions.is_some() && Upstreams::matches(&rule.package, package)).collect();
+
+        if matching.is_empty() {
+            return vec![];
+        }
+
+        return packument["versions"].as_object()
+            .map(|versions| versions.keys().filter(|version| matching.iter().any(|rule| rule.denies_version(version))).cloned().collect())
+            .unwrap_or_default();
+    }
+
+    /// The version of a tarball file, `name-1.0.0.tgz` or `@scope/name-1.0.0.tgz`.
+    pub fn tarball_version(package: &str, file: &str) -> Option<String> {
+        let name = package.rsplit('/').next()?;
+        let file = file.rsplit('/').next()?;
+        return file.strip_prefix(name)?.strip_prefix('-')?.strip_suffix(".tgz").map(|version| version.to_string());
+    }
+
+    async fn reload(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        let modified = match fs::metadata(path).await.and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(error) => {
+                println!("Could not read policy {}: {error}", path.display());
+                return;
+            }
+        };
+
+        if *self.modified.read().await == Some(modified) {
+            return;
+        }
+
+        let content = match fs::read_to_string(path).await {
+            Ok(content) => content,
+            Err(error) => {
+                println!("Could not read policy {}: {error}", path.display());
+                return;
+            }
+        };
+
+        // A broken file keeps the previous rules in place rather than opening everything up,
+        // it is reported once and read again when it changes.
+        let rules = match Policy::parse(&content) {
+            Ok(rules) => rules,
+            Err(error) => {
+                println!("Ignoring policy {}, it {error}", path.display());
+                *self.modified.write().await = Some(modified);
+                return;
+            }
+        };
+
+        println!("Loaded policy with {} allowed patterns and {} deny rules", rules.allow.len(), rules.deny.len());
+
+        *self.rules.write().await = rules;
+        *self.modified.write().await = Some(modified);
+    }
+
+    /// Compiles the content of a policy file, an empty one allows everything.
+    fn parse(content: &str) -> Result<Rules, String> {
+        let file: PolicyFile = serde_yaml::from_str::<Option<PolicyFile>>(content)
+            .map_err(|error| format!("does not parse: {error}"))?
+            .unwrap_or_default();
+
+        let mut deny = Vec::new();
+
+        for rule in file.deny {
+            let versions = match &rule.versions {
+                Some(range) => Some((range.clone(), Policy::parse_range(range).ok_or(format!("has {range}, which is not a semver range"))?)),
+                None => None
+            };
+
+            deny.push(Rule { package: rule.package, versions, reason: rule.reason });
+        }
+
+        return Ok(Rules { allow: file.allow, deny });
+    }
+
+    /// Translates an npm range (`<1.2.3 || 2.x`, `1.0.0 - 1.4.0`, `1.2.3`) into the comma
+    /// separated requirements of the semver crate, one per `||` alternative.
+    pub fn parse_range(range: &str) -> Option<Vec<VersionReq>> {
+        return range.split("||").map(|alternative| {
+            let alternative = alternative.trim();
+
+            if let Some((from, to)) = alternative.split_once(" - ") {
+                return VersionReq::parse(&format!(">={}, <={}", from.trim(), to.trim())).ok();
+            }
+
+            let mut comparators: Vec<String> = Vec::new();
+            let mut operator = String::new();
+
+            for token in alternative.split_whitespace() {
+                // `>= 1.2.3` is written with a space at times.
+                if token.chars().all(|character| "<>=~^".contains(character)) {
+                    operator = token.to_string();
+                    continue;
+                }
+
+                let token = operator.clone() + token.trim_start_matches('v');
+                operator.clear();
+
+                // A bare version is exact in npm but a caret requirement in the semver crate.
+                let exact = token.starts_with(|first: char| first.is_ascii_digit()) && !token.contains(['x', 'X', '*']);
+                comparators.push(if exact { "=".to_string() + &token } else { token });
+            }
+
+            if comparators.is_empty() {
+                comparators.push("*".to_string());
+            }
+
+            return VersionReq::parse(&comparators.join(", ")).ok();
+        }).collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allows(range: &str, version: &str) -> bool {
+        let version = Version::parse(version).unwrap();
+        return Policy::parse_range(range).unwrap().iter().any(|requirement| requirement.matches(&version));
+    }
+
+    #[test]
+    fn bare_versions_are_exact() {
+        assert!(allows("1.2.3", "1.2.3"));
+        assert!(!allows("1.2.3", "1.3.0"));
+        assert!(allows("v1.2.3", "1.2.3"));
+        assert!(!allows("=1.2.3", "1.2.4"));
+    }
+
+    #[test]
+    fn reads_alternatives() {
+        assert!(allows("<1.2.3 || 2.x", "1.0.0"));
+        assert!(allows("<1.2.3 || 2.x", "2.5.0"));
+        assert!(!allows("<1.2.3 || 2.x", "1.5.0"));
+        assert!(!allows("<1.2.3 || 2.x", "3.0.0"));
+    }
+
+    #[test]
+    fn reads_hyphen_ranges() {
+        assert!(allows("1.0.0 - 1.4.0", "1.0.0"));
+        assert!(allows("1.0.0 - 1.4.0", "1.4.0"));
+        assert!(!allows("1.0.0 - 1.4.0", "1.4.1"));
+        assert!(!allows("1.0.0 - 1.4.0", "0.9.9"));
+    }
+
+    #[test]
+    fn reads_spaced_operators_and_intersections() {
+        assert!(allows(">= 1.2.3 <2.0.0", "1.9.9"));
+        assert!(!allows(">= 1.2.3 <2.0.0", "2.0.0"));
+        assert!(!allows(">= 1.2.3 <2.0.0", "1.2.2"));
+        assert!(allows("^1.2.3", "1.9.0"));
+        assert!(!allows("~1.2.3", "1.3.0"));
+    }
+
+    #[test]
+    fn empty_ranges_allow_everything() {
+        assert!(allows("", "0.0.1"));
+        assert!(allows("*", "99.0.0"));
+    }
+
+    #[test]
+    fn rejects_what_is_not_a_range() {
+        assert!(Policy::parse_range("latest").is_none());
+        assert!(Policy::parse_range("1.0.0 || banana").is_none());
+    }
+
+    #[test]
+    fn compiles_policy_files() {
+        let rules = Policy::parse("allow:\n  - \"@veto/*\"\ndeny:\n  - package: event-stream\n    versions: 3.3.6\n  - package: \"*-malware\"\n").unwrap();
+
+        assert_eq!(rules.allow, vec!["@veto/*"]);
+        assert_eq!(rules.deny.len(), 2);
+        assert!(rules.deny[0].denies_version("3.3.6"));
+        assert!(!rules.deny[0].denies_version("3.3.5"));
+        assert!(rules.deny[1].denies_version("1.0.0"));
+    }
+
+    #[test]
+    fn empty_policy_files_allow_everything() {
+        let rules = Policy::parse("").unwrap();
+
+        assert!(rules.allow.is_empty());
+        assert!(rules.deny.is_empty());
+    }
+
+    #[test]
+    fn refuses_broken_policy_files() {
+        assert!(Policy::parse("deny: [").err().unwrap().starts_with("does not parse"));
+        assert!(Policy::parse("deny:\n  - package: lodash\n    versions: latest\n").err().unwrap().contains("latest, which is not a semver range"));
+    }
+}
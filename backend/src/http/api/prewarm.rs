@@ -0,0 +1,184 @@
+use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
+
+use futures::StreamExt;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::Mutex;
+
+use crate::{config::Config, http::api::{api::Api, download::ApiStream, lockfile::{self, Locked}}};
+
+/// Finished jobs are kept this long for polling.
+const JOB_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+
+#[derive(Serialize, Clone)]
+pub struct Failure {
+    pub package: String,
+    pub version: String,
+    pub reason: String,
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct Progress {
+    pub id: String,
+    pub done: bool,
+    pub total: usize,
+    /// Tarballs that had to be fetched from upstream.
+    pub fetched: usize,
+    /// Tarballs that were in the cache already.
+    pub cached: usize,
+    pub failed: Vec<Failure>,
+    #[serde(skip)]
+    finished_at: Option<Instant>,
+}
+
+enum Outcome {
+    Fetched,
+    Cached,
+    Failed(String)
+}
+
+/// Fetches everything a lockfile resolves to through the regular load path, so a later
+/// install is served from the cache even if upstream is gone.
+#[derive(Clone)]
+pub struct Prewarm {
+    jobs: Arc<Mutex<HashMap<String, Arc<Mutex<Progress>>>>>,
+    concurrency: usize,
+}
+
+impl Prewarm {
+    pub fn new(config: &Config) -> Self {
+        return Self {
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+            concurrency: config.prewarm_concurrency.max(1)
+        };
+    }
+
+    /// Starts a job for the lockfile and returns its id.
+    pub async fn start(&self, api: Api, content: &str) -> Result<String, String> {
+        let locked = lockfile::parse(content)?;
+        let id = uuid::Uuid::new_v4().to_string();
+
+        let progress = Arc::new(Mutex::new(Progress { id: id.clone(), total: locked.len(), ..Default::default() }));
+
+        {
+            let mut jobs = self.jobs.lock().await;
+            let mut expired: Vec<String> = Vec::new();
+
+            for (id, job) in jobs.iter() {
+                if job.lock().await.finished_at.is_some_and(|finished_at| finished_at.elapsed() > JOB_RETENTION) {
+                    expired.push(id.clone());
+                }
+            }
+
+            for id in expired {
+                jobs.remove(&id);
+            }
+
+            jobs.insert(id.clone(), progress.clone());
+        }
+
+        let concurrency = self.concurrency;
+        tokio::spawn(async move {
+            Prewarm::run(api, locked, progress, concurrency).await;
+        });
+
+        return Ok(id);
+    }
+
+    pub async fn progress(&self, id: &str) -> Option<Progress> {
+        let job = self.jobs.lock().await.get(id)?.clone();
+        return Some(job.lock().await.clone());
+    }
+
+    async fn run(api: Api, locked: Vec<Locked>, progress: Arc<Mutex<Progress>>, concurrency: usize) {
+        let mut names: Vec<String> = locked.iter().map(|locked| locked.name.clone()).collect();
+        names.dedup();
+
+        // Packuments first, each once, then the tarballs of every version.
+        let packuments: HashMap<String, Result<Value, String>> = futures::stream::iter(names)
+            .map(|name| {
+                let api = api.clone();
+                return async move {
+                    let packument = Prewarm::packument(api, &name).await;
+                    return (name, packument);
+                };
+            })
+            .buffer_unordered(concurrency)
+            .collect()
+            .await;
+
+        futures::stream::iter(locked)
+            .for_each_concurrent(concurrency, |Locked { name, version }| {
+                let api = api.clone();
+                let progress = progress.clone();
+                let packument = &packuments[&name];
+
+                return async move {
+                    let outcome = match packument {
+                        Ok(packument) => Prewarm::tarball(api, &name, &version, packument).await,
+                        Err(reason) => Outcome::Failed(reason.clone())
+                    };
+
+                    let mut progress = progress.lock().await;
+
+                    match outcome {
+                        Outcome::Fetched => progress.fetched += 1,
+                        Outcome::Cached => progress.cached += 1,
+                        Outcome::Failed(reason) => progress.failed.push(Failure { package: name, version, reason })
+                    }
+                };
+            })
+            .await;
+
+        let mut progress = progress.lock().await;
+        println!("Prewarm {} done: {} fetched, {} cached, {} failed", progress.id, progress.fetched, progress.cached, progress.failed.len());
+        progress.done = true;
+        progress.finished_at = Some(Instant::now());
+    }
+
+    async fn packument(mut api: Api, name: &str) -> Result<Value, String> {
+        let stored = api.get_package_metadata(name.to_string()).await.map_err(|error| error.to_string())?;
+        let packument: Value = serde_json::from_slice(&stored.body).map_err(|_| "packument is not json".to_string())?;
+
+        if packument.get("versions").is_none() {
+            return Err("package not found".to_string());
+        }
+
+        return Ok(packument);
+    }
+
+    async fn tarball(mut api: Api, name: &str, version: &str, packument: &Value) -> Outcome {
+        let Some(tarball) = packument["versions"][version]["dist"]["tarball"].as_str() else {
+            return Outcome::Failed("version not found".to_string());
+        };
+
+        let Some((_, file)) = tarball.split_once("/-/") else {
+            return Outcome::Failed(format!("unexpected tarball url {tarball}"));
+        };
+
+        let file = urlencoding::decode(file).map(|file| file.to_string()).unwrap_or(file.to_string());
+
+        let stored = match api.get_file(name.to_string(), file).await {
+            Ok(stored) => stored,
+            Err(error) => return Outcome::Failed(error.to_string())
+        };
+
+        return match stored.stream {
+            Some(ApiStream::Blob(..)) => Outcome::Cached,
+            // Read to the end, the download only counts once it is verified and stored.
+            Some(stream) => {
+                let mut stream = stream.into_stream();
+
+                while let Some(chunk) = stream.next().await {
+                    if let Err(error) = chunk {
+                        return Outcome::Failed(error.to_string());
+                    }
+                }
+
+                Outcome::Fetched
+            },
+            None => Outcome::Failed("upstream has no such tarball".to_string())
+        };
+    }
+}
@@ -0,0 +1,241 @@
+use std::{collections::{HashMap, HashSet}, io, sync::Arc, time::{Duration, Instant}};
+
+use chrono::DateTime;
+use serde_json::Value;
+use tokio::sync::{Mutex, RwLock};
+
+use crate::{config::Config, http::api::{entry, freshness::FreshnessPolicy, storage::ApiStorage, store::CacheStore, upstream::Upstreams}};
+
+/// Versions released before their quarantine ended, as `name@version`, shared by all replicas.
+const KEY: &str = "-/quarantine-releases";
+
+
+/// Holds back versions published less than a minimum age ago, a compromised release is
+/// usually found and unpublished within days. The age comes from the packument's `time`,
+/// versions without a publish time are served. Only packuments are filtered, tarballs stay
+/// available so lockfiles that already pin a version keep installing.
+#[derive(Clone)]
+pub struct Quarantine {
+    min_age: Duration,
+    /// Package patterns with their own minimum age, the first matching one wins.
+    overrides: Vec<(String, Duration)>,
+    store: Arc<dyn CacheStore>,
+    ttl: Duration,
+    releases: Arc<RwLock<(Option<Instant>, HashSet<String>)>>,
+    /// Held while the releases are read, changed and written back.
+    writing: Arc<Mutex<()>>,
+}
+
+impl Quarantine {
+    pub fn new(config: &Config, store: Arc<dyn CacheStore>) -> Self {
+        return Self {
+            min_age: Duration::from_secs(config.min_age),
+            overrides: config.min_age_overrides.iter().map(|(pattern, seconds)| (pattern.clone(), Duration::from_secs(*seconds))).collect(),
+            store,
+            ttl: Duration::from_secs(config.metadata_ttl),
+            releases: Arc::new(RwLock::new((None, HashSet::new()))),
+            writing: Arc::new(Mutex::new(()))
+        };
+    }
+
+    fn min_age_of(&self, package: &str) -> Duration {
+        return self.overrides.iter()
+            .find(|(pattern, _)| Upstreams::matches(pattern, package))
+            .map(|(_, min_age)| *min_age)
+            .unwrap_or(self.min_age);
+    }
+
+    /// Versions of the packument still in quarantine, leaving out `exempt` ones.
+    pub async fn held_back(&self, package: &str, packument: &Value, exempt: &HashSet<String>) -> Vec<String> {
+        let min_age = self.min_age_of(package);
+
+        if min_age.is_zero() {
+            return vec![];
+        }
+
+        let (Some(versions), Some(time)) = (packument["versions"].as_object(), packument["time"].as_object()) else {
+            return vec![];
+        };
+
+        let now = FreshnessPolicy::now();
+        let held: Vec<String> = versions.keys()
+            .filter(|version| !exempt.contains(*version))
+            .filter(|version| {
+                let published = time.get(*version).and_then(|published| published.as_str()).and_then(|published| DateTime::parse_from_rfc3339(published).ok());
+                return published.is_some_and(|published| now - published.timestamp() < min_age.as_secs() as i64);
+            })
+            .cloned()
+            .collect();
+
+        if held.is_empty() {
+            return held;
+        }
+
+        let releases = self.releases().await;
+        return held.into_iter().filter(|version| !releases.contains(&(package.to_string() + "@" + version))).collect();
+    }
+
+    /// Lets a version through before its quarantine ends.
+    pub async fn release(&self, package: &str, version: &str) -> Result<(), io::Error> {
+        let _writing = self.writing.lock().await;
+        let mut releases = self.load().await?;
+
+        if !releases.insert(package.to_string() + "@" + version) {
+            return Ok(());
+        }
+
+        let stored = ApiStorage {
+            headers: HashMap::from([("content-type".to_string(), b"application/json".to_vec())]),
+            body: serde_json::to_vec(&releases).unwrap(),
+            stored_at: FreshnessPolicy::now(),
+            integrity: None,
+            stream: None
+        };
+
+        self.store.store(KEY, entry::encode(&stored)).await?;
+        *self.releases.write().await = (Some(Instant::now()), releases);
+        return Ok(());
+    }
+
+    async fn releases(&self) -> HashSet<String> {
+        {
+            let releases = self.releases.read().await;
+
+            if releases.0.is_some_and(|loaded| loaded.elapsed() < self.ttl) {
+                return releases.1.clone();
+            }
+        }
+
+        return match self.load().await {
+            Ok(releases) => {
+                *self.releases.write().await = (Some(Instant::now()), releases.clone());
+                releases
+            },
+            Err(error) => {
+                println!("Could not read quarantine releases: {error}");
+                self.releases.read().await.1.clone()
+            }
+        };
+    }
+
+    async fn load(&self) -> Result<HashSet<String>, io::Error> {
+        let Some(value) = self.store.load(KEY).await? else {
+            return Ok(HashSet::new());
+        };
+
+        let stored = entry::decode(&value).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error.to_string()))?;
+        return serde_json::from_slice(&stored.body).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::{Path, PathBuf}};
+
+    use chrono::{TimeDelta, Utc};
+    use serde_json::json;
+
+    use crate::http::api::{inner::ApiInner, store::fs::FsStore};
+
+    use super::*;
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    fn quarantine(root: &Path) -> Quarantine {
+        return Quarantine {
+            min_age: Duration::from_secs(7 * DAY),
+            overrides: vec![("@veto/*".to_string(), Duration::ZERO), ("left-*".to_string(), Duration::from_secs(DAY))],
+            store: Arc::new(FsStore::new(root)),
+            ttl: Duration::from_secs(60),
+            releases: Arc::new(RwLock::new((None, HashSet::new()))),
+            writing: Arc::new(Mutex::new(()))
+        };
+    }
+
+    fn root() -> PathBuf {
+        return env::temp_dir().join(format!("npm-proxy-quarantine-{}", rand::random::<u64>()));
+    }
+
+    fn days_ago(days: i64) -> String {
+        return (Utc::now() - TimeDelta::days(days)).to_rfc3339();
+    }
+
+    /// 1.0.0 is a month old, 1.1.0 three days, 2.0.0 from today and the latest.
+    fn packument() -> Value {
+        return json!({
+            "dist-tags": { "latest": "2.0.0" },
+            "versions": { "0.9.0": {}, "1.0.0": {}, "1.1.0": {}, "2.0.0": {} },
+            "time": { "1.0.0": days_ago(30), "1.1.0": days_ago(3), "2.0.0": days_ago(0), "modified": days_ago(0) }
+        });
+    }
+
+    fn sorted(mut versions: Vec<String>) -> Vec<String> {
+        versions.sort();
+        return versions;
+    }
+
+    #[tokio::test]
+    async fn holds_back_recent_versions() {
+        let root = root();
+        let quarantine = quarantine(&root);
+
+        assert_eq!(sorted(quarantine.held_back("lodash", &packument(), &HashSet::new()).await), vec!["1.1.0", "2.0.0"]);
+        assert_eq!(quarantine.held_back("lodash", &packument(), &HashSet::from(["2.0.0".to_string()])).await, vec!["1.1.0"]);
+        assert!(quarantine.held_back("lodash", &json!({ "versions": { "1.0.0": {} } }), &HashSet::new()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn applies_the_first_matching_override() {
+        let root = root();
+        let quarantine = quarantine(&root);
+
+        assert_eq!(quarantine.min_age_of("@veto/core"), Duration::ZERO);
+        assert_eq!(quarantine.min_age_of("left-pad"), Duration::from_secs(DAY));
+        assert_eq!(quarantine.min_age_of("lodash"), Duration::from_secs(7 * DAY));
+
+        assert!(quarantine.held_back("@veto/core", &packument(), &HashSet::new()).await.is_empty());
+        assert_eq!(quarantine.held_back("left-pad", &packument(), &HashSet::new()).await, vec!["2.0.0"]);
+    }
+
+    #[tokio::test]
+    async fn releases_versions_for_every_replica() {
+        let root = root();
+        tokio::fs::create_dir_all(&root).await.unwrap();
+        quarantine(&root).release("lodash", "2.0.0").await.unwrap();
+
+        // A fresh instance, as another replica, reads the releases from the store.
+        let replica = quarantine(&root);
+        assert_eq!(replica.held_back("lodash", &packument(), &HashSet::new()).await, vec!["1.1.0"]);
+        assert_eq!(sorted(replica.held_back("underscore", &packument(), &HashSet::new()).await), vec!["1.1.0", "2.0.0"]);
+
+        tokio::fs::remove_dir_all(root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn latest_moves_back_to_a_released_version() {
+        let root = root();
+        let mut packument = packument();
+        let held = quarantine(&root).held_back("lodash", &packument, &HashSet::new()).await;
+
+        ApiInner::hide_versions(&mut packument, &held);
+
+        assert_eq!(packument["dist-tags"]["latest"], "1.0.0");
+        assert_eq!(sorted(packument["versions"].as_object().unwrap().keys().cloned().collect()), vec!["0.9.0", "1.0.0"]);
+    }
+
+    #[tokio::test]
+    async fn every_version_in_quarantine_leaves_no_latest() {
+        let root = root();
+        let mut packument = json!({
+            "dist-tags": { "latest": "1.1.0", "next": "2.0.0-rc.1" },
+            "versions": { "1.0.0": {}, "1.1.0": {}, "2.0.0-rc.1": {} },
+            "time": { "1.0.0": days_ago(2), "1.1.0": days_ago(1), "2.0.0-rc.1": days_ago(0) }
+        });
+        let held = quarantine(&root).held_back("lodash", &packument, &HashSet::new()).await;
+
+        ApiInner::hide_versions(&mut packument, &held);
+
+        assert_eq!(packument["versions"], json!({}));
+        assert_eq!(packument["dist-tags"], json!({}));
+    }
+}
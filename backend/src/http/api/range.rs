@@ -0,0 +1,181 @@
+use axum::{body::Body, http::{header::{IF_RANGE, RANGE}, HeaderMap, Response, StatusCode}, response::IntoResponse};
+
+use crate::http::api::{download::ApiStream, storage::ApiStorage};
+
+
+/// An inclusive byte range of a body.
+#[derive(PartialEq, Eq, Debug)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum Requested {
+    /// No range, one the proxy does not serve (several at once) or a malformed one, the
+    /// whole body is sent.
+    Whole,
+    Range(ByteRange),
+    /// The range starts past the end of the body.
+    Unsatisfiable
+}
+
+/// Reads a `Range: bytes=...` header for a body of `length` bytes.
+pub fn parse(header: &str, length: u64) -> Requested {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return Requested::Whole;
+    };
+
+    // Multipart responses are not supported, several ranges get the whole body.
+    if spec.contains(',') {
+        return Requested::Whole;
+    }
+
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Requested::Whole;
+    };
+
+    let range = match (start.parse::<u64>().ok(), end.parse::<u64>().ok()) {
+        // `bytes=-500`, the last 500 bytes.
+        (None, Some(suffix)) if start.is_empty() => {
+            if suffix == 0 || length == 0 {
+                return Requested::Unsatisfiable;
+            }
+
+            ByteRange { start: length.saturating_sub(suffix), end: length - 1 }
+        },
+        (Some(start), None) if end.is_empty() => ByteRange { start, end: length.saturating_sub(1) },
+        (Some(start), Some(end)) if start <= end => ByteRange { start, end: end.min(length.saturating_sub(1)) },
+        _ => return Requested::Whole
+    };
+
+    if range.start >= length {
+        return Requested::Unsatisfiable;
+    }
+
+    return Requested::Range(range);
+}
+
+/// Answers a tarball request. Tarballs in the cache advertise `accept-ranges` and carry
+/// their digest as etag, so an interrupted download can be resumed with `Range` and
+/// `If-Range`. Tarballs still streaming from upstream are always sent whole.
+pub fn respond(mut stored: ApiStorage, request: &HeaderMap) -> Response<Body> {
+    let Some(ApiStream::Blob(_, _, length)) = &stored.stream else {
+        return stored.into_response();
+    };
+
+    let length = *length;
+
+    if let Some(integrity) = &stored.integrity {
+        stored.headers.insert("etag".to_string(), format!("\"{}\"", integrity.hex()).into_bytes());
+    }
+
+    stored.headers.insert("accept-ranges".to_string(), b"bytes".to_vec());
+
+    let Some(range) = request.get(RANGE).and_then(|range| range.to_str().ok()) else {
+        return stored.into_response();
+    };
+
+    // The client's partial copy is of another version of the tarball.
+    if let Some(if_range) = request.get(IF_RANGE) && !is_current(&stored, if_range.as_bytes()) {
+        return stored.into_response();
+    }
+
+    return match parse(range, length) {
+        Requested::Whole => stored.into_response(),
+        Requested::Unsatisfiable => Response::builder()
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header("content-range", format!("bytes */{length}"))
+            .body(Body::empty())
+            .unwrap(),
+        Requested::Range(ByteRange { start, end }) => {
+            let mut builder = Response::builder().status(StatusCode::PARTIAL_CONTENT);
+
+            for (key, value) in stored.headers.into_iter() {
+                builder = builder.header(key, value);
+            }
+
+            let stream = stored.stream.unwrap().into_range_stream(start, end - start + 1);
+
+            builder
+                .header("content-range", format!("bytes {start}-{end}/{length}"))
+                .header("content-length", end - start + 1)
+                .body(Body::from_stream(stream))
+                .unwrap()
+        }
+    };
+}
+
+/// `If-Range` holds either the etag or the last-modified date the client saw. Weak etags
+/// never match.
+fn is_current(stored: &ApiStorage, if_range: &[u8]) -> bool {
+    if if_range.starts_with(b"W/") {
+        return false;
+    }
+
+    return stored.header("etag").is_some_and(|etag| etag == if_range)
+        || stored.header("last-modified").is_some_and(|last_modified| last_modified == if_range);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn range(start: u64, end: u64) -> Requested {
+        return Requested::Range(ByteRange { start, end });
+    }
+
+    #[test]
+    fn reads_ranges() {
+        assert_eq!(parse("bytes=0-99", 1000), range(0, 99));
+        assert_eq!(parse(" bytes=500- ", 1000), range(500, 999));
+        assert_eq!(parse("bytes=900-5000", 1000), range(900, 999));
+        assert_eq!(parse("bytes=999-999", 1000), range(999, 999));
+    }
+
+    #[test]
+    fn reads_suffixes() {
+        assert_eq!(parse("bytes=-100", 1000), range(900, 999));
+        assert_eq!(parse("bytes=-5000", 1000), range(0, 999));
+        assert_eq!(parse("bytes=-0", 1000), Requested::Unsatisfiable);
+        assert_eq!(parse("bytes=-100", 0), Requested::Unsatisfiable);
+    }
+
+    #[test]
+    fn sends_several_or_malformed_ranges_whole() {
+        assert_eq!(parse("bytes=0-99,200-299", 1000), Requested::Whole);
+        assert_eq!(parse("items=0-99", 1000), Requested::Whole);
+        assert_eq!(parse("bytes=99-0", 1000), Requested::Whole);
+        assert_eq!(parse("bytes=a-b", 1000), Requested::Whole);
+        assert_eq!(parse("bytes=100", 1000), Requested::Whole);
+        assert_eq!(parse("bytes=-", 1000), Requested::Whole);
+    }
+
+    #[test]
+    fn refuses_ranges_past_the_end() {
+        assert_eq!(parse("bytes=1000-", 1000), Requested::Unsatisfiable);
+        assert_eq!(parse("bytes=1000-1999", 1000), Requested::Unsatisfiable);
+        assert_eq!(parse("bytes=0-", 0), Requested::Unsatisfiable);
+    }
+
+    #[test]
+    fn matches_if_range_strongly() {
+        let stored = ApiStorage {
+            headers: HashMap::from([
+                ("etag".to_string(), b"\"abc\"".to_vec()),
+                ("last-modified".to_string(), b"Tue, 15 Nov 1994 12:45:26 GMT".to_vec())
+            ]),
+            body: Vec::new(),
+            stored_at: 0,
+            integrity: None,
+            stream: None
+        };
+
+        assert!(is_current(&stored, b"\"abc\""));
+        assert!(is_current(&stored, b"Tue, 15 Nov 1994 12:45:26 GMT"));
+        assert!(!is_current(&stored, b"W/\"abc\""));
+        assert!(!is_current(&stored, b"\"def\""));
+    }
+}
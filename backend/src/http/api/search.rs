@@ -0,0 +1,193 @@
+use std::{collections::HashMap, sync::Arc};
+
+use chrono::Utc;
+use serde_json::{json, Value};
+use tokio::sync::RwLock;
+
+use crate::http::api::{freshness::ResourceKind, local::LOCAL_PREFIX};
+
+
+/// What a search result shows for a package, taken from its packument.
+#[derive(Clone)]
+struct Document {
+    /// The `package` object of npm's search result.
+    package: Value,
+    name: String,
+    description: String,
+    keywords: Vec<String>,
+    maintainers: Vec<String>,
+}
+
+/// Searches every packument the cache holds, local and upstream ones, by name,
+/// description, keywords and maintainers. Packuments are indexed as installs see them.
+#[derive(Clone)]
+pub struct SearchIndex {
+    documents: Arc<RwLock<HashMap<String, Document>>>,
+}
+
+impl SearchIndex {
+    pub fn new() -> Self {
+        return Self { documents: Arc::new(RwLock::new(HashMap::new())) };
+    }
+
+    /// Whether the cache entry at `uri` is a packument.
+    pub fn indexes(uri: &str) -> bool {
+        let uri = uri.strip_prefix(LOCAL_PREFIX).unwrap_or(uri);
+        return !uri.starts_with("-/") && ResourceKind::of(uri) == ResourceKind::Metadata;
+    }
+
+    pub async fn update(&self, packument: &Value) {
+        if let Some(document) = SearchIndex::document(packument) {
+            self.documents.write().await.insert(document.name.clone(), document);
+        }
+    }
+
+    pub async fn remove(&self, name: &str) {
+        self.documents.write().await.remove(name);
+    }
+
+    fn document(packument: &Value) -> Option<Document> {
+        let name = packument.get("name")?.as_str()?.to_string();
+        let latest = packument["dist-tags"]["latest"].as_str().unwrap_or_default();
+        let manifest = &packument["versions"][latest];
+
+        let field = |key: &str| -> Value {
+            return packument.get(key).filter(|value| !value.is_null()).unwrap_or(&manifest[key]).clone();
+        };
+
+        let description = field("description");
+        let keywords = Some(field("keywords")).filter(|keywords| keywords.is_array()).unwrap_or(json!([]));
+        let maintainers = Some(field("maintainers")).filter(|maintainers| maintainers.is_array()).unwrap_or(json!([]));
+
+        let links = json!({
+            "npm": format!("https://www.npmjs.com/package/{name}"),
+            "homepage": field("homepage"),
+            "repository": field("repository").get("url").cloned().unwrap_or(field("repository")),
+            "bugs": field("bugs").get("url").cloned().unwrap_or(Value::Null)
+        });
+
+        let date = packument["time"].get(latest).or(packument["time"].get("modified")).cloned().unwrap_or(Value::Null);
+
+        let package = json!({
+            "name": name,
+            "scope": name.strip_prefix('@').and_then(|scoped| scoped.split_once('/')).map(|(scope, _)| scope).unwrap_or("unscoped"),
+            "version": latest,
+            "description": description,
+            "keywords": keywords,
+            "date": date,
+            "links": links,
+            "publisher": manifest.get("_npmUser").cloned().unwrap_or(Value::Null),
+            "maintainers": maintainers
+        });
+
+        return Some(Document {
+            description: description.as_str().unwrap_or_default().to_lowercase(),
+            keywords: keywords.as_array().map(|keywords| keywords.iter().filter_map(|keyword| keyword.as_str()).map(|keyword| keyword.to_lowercase()).collect()).unwrap_or_default(),
+            maintainers: maintainers.as_array().map(|maintainers| maintainers.iter().filter_map(|maintainer| maintainer.get("name").or(maintainer.get("username")).and_then(|name| name.as_str())).map(|name| name.to_lowercase()).collect()).unwrap_or_default(),
+            name: name.clone(),
+            package
+        });
+    }
+
+    /// Scores `document` for the query, `None` when a term does not match. Terms may be
+    /// qualified like npm's: `keywords:`, `maintainer:`, `author:` and `scope:`.
+    fn score(document: &Document, terms: &[String]) -> Option<f64> {
+        let name = document.name.to_lowercase();
+        let mut score = 0.0;
+
+        for term in terms {
+            if let Some((qualifier, value)) = term.split_once(':') {
+                let matches = match qualifier {
+                    "keywords" => value.split(',').any(|keyword| document.keywords.iter().any(|known| known == keyword)),
+                    "maintainer" | "author" => document.maintainers.iter().any(|maintainer| maintainer == value),
+                    "scope" => name.starts_with(&("@".to_string() + value.trim_start_matches('@') + "/")),
+                    _ => false
+                };
+
+                if !matches {
+                    return None;
+                }
+
+                score += 1.0;
+                continue;
+            }
+
+            let mut term_score: f64 = 0.0;
+
+            if name == *term || name.rsplit('/').next() == Some(term.as_str()) {
+                term_score += 100.0;
+            } else if name.contains(term.as_str()) {
+                term_score += 10.0;
+            }
+
+            if document.keywords.iter().any(|keyword| keyword == term) {
+                term_score += 5.0;
+            }
+
+            if document.description.contains(term.as_str()) {
+                term_score += 1.0;
+            }
+
+            if term_score == 0.0 {
+                return None;
+            }
+
+            score += term_score;
+        }
+
+        return Some(score);
+    }
+
+    /// Returns the page of npm search result objects and the total number of matches.
+    pub async fn search(&self, text: &str, from: usize, size: usize) -> (Vec<Value>, usize) {
+        let terms: Vec<String> = text.to_lowercase().split_whitespace().map(|term| term.to_string()).collect();
+        let documents = self.documents.read().await;
+
+        let mut matches: Vec<(f64, &Document)> = documents.values()
+            .filter_map(|document| SearchIndex::score(document, &terms).map(|score| (score, document)))
+            .collect();
+
+        matches.sort_by(|(left_score, left), (right_score, right)| right_score.total_cmp(left_score).then_with(|| left.name.cmp(&right.name)));
+
+        let best = matches.first().map(|(score, _)| *score).unwrap_or(1.0).max(1.0);
+        let total = matches.len();
+
+        let objects = matches.into_iter().skip(from).take(size).map(|(score, document)| {
+            let relative = score / best;
+
+            return json!({
+                "package": document.package,
+                "score": { "final": relative, "detail": { "quality": relative, "popularity": relative, "maintenance": relative } },
+                "searchScore": score
+            });
+        }).collect();
+
+        return (objects, total);
+    }
+
+    /// Local results first, then upstream ones for packages the cache does not know. Both
+    /// are expected to start at the first result, the page is cut from the merged list.
+    pub fn merge(local: Vec<Value>, local_total: usize, upstream: Option<Value>, from: usize, size: usize) -> Value {
+        let upstream = upstream.unwrap_or(Value::Null);
+        let mut objects = local;
+        let known: Vec<String> = objects.iter().filter_map(|object| object["package"]["name"].as_str().map(|name| name.to_string())).collect();
+        let mut duplicates = 0;
+
+        for object in upstream["objects"].as_array().cloned().unwrap_or_default() {
+            if object["package"]["name"].as_str().is_some_and(|name| known.iter().any(|known| known == name)) {
+                duplicates += 1;
+                continue;
+            }
+
+            objects.push(object);
+        }
+
+        let total = (local_total + upstream["total"].as_u64().unwrap_or(0) as usize).saturating_sub(duplicates);
+
+        return json!({
+            "objects": objects.into_iter().skip(from).take(size).collect::<Vec<Value>>(),
+            "total": total,
+            "time": Utc::now().to_rfc2822()
+        });
+    }
+}
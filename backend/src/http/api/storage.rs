@@ -1,13 +1,34 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
 use axum::{body::Body, http::Response, response::IntoResponse};
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
 
+use crate::http::api::{download::ApiStream, integrity::Integrity};
+
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ApiStorage {
     pub headers: HashMap<String, Vec<u8>>,
     pub body: Vec<u8>,
+    /// Unix timestamp (seconds) of the last time upstream confirmed this entry.
+    pub stored_at: i64,
+    /// Digest of the tarball body kept in the blob store.
+    pub integrity: Option<Integrity>,
+    /// Tarball bodies are not kept in `body` but streamed from disk or from a running download.
+    #[serde(skip)]
+    pub stream: Option<ApiStream>,
+}
+
+impl ApiStorage {
+    pub fn header(&self, name: &str) -> Option<&Vec<u8>> {
+        return self.headers.get(name);
+    }
+
+    pub fn age(&self) -> Duration {
+        let seconds = Utc::now().timestamp() - self.stored_at;
+        return Duration::from_secs(seconds.max(0) as u64);
+    }
 }
 
 
@@ -16,11 +37,19 @@ impl IntoResponse for ApiStorage {
         let mut builder = Response::builder();
 
         for (key, value) in self.headers.into_iter() {
-            builder = builder.header(key, value);   
+            builder = builder.header(key, value);
         }
-        
+
+        if let Some(stream) = self.stream {
+            if let Some(length) = stream.length() {
+                builder = builder.header("content-length", length);
+            }
+
+            return builder.body(Body::from_stream(stream.into_stream())).unwrap();
+        }
+
         builder = builder.header("content-length", self.body.len());
 
         return builder.body(Body::from(self.body)).unwrap();
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,163 @@
+use std::{io::{self, SeekFrom}, path::{Path, PathBuf}, time::UNIX_EPOCH};
+
+use base64::{prelude::BASE64_URL_SAFE, Engine};
+use bytes::Bytes;
+use futures::{future::BoxFuture, FutureExt, StreamExt};
+use tokio::{fs::{self, File}, io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt}};
+
+use crate::http::api::store::{BlobStat, BlobStream, CacheStore};
+
+
+/// Keeps entries as `<base64 uri>.bin` and blobs under `blobs/` in the local cache directory.
+pub struct FsStore {
+    root: PathBuf,
+}
+
+impl FsStore {
+    pub fn new(root: &Path) -> Self {
+        return Self { root: root.to_path_buf() };
+    }
+
+    fn entry_path(&self, key: &str, extension: &str) -> PathBuf {
+        // Url safe, the standard alphabet contains `/` which would point into a subdirectory.
+        return self.root.join(BASE64_URL_SAFE.encode(key) + extension);
+    }
+
+    fn blob_path(&self, name: &str) -> PathBuf {
+        return self.root.join("blobs").join(name);
+    }
+}
+
+impl CacheStore for FsStore {
+    fn load(&self, key: &str) -> BoxFuture<'_, Result<Option<Vec<u8>>, io::Error>> {
+        let path = self.entry_path(key, ".bin");
+
+        return async move {
+            return match fs::read(path).await {
+                Ok(data) => Ok(Some(data)),
+                Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(error) => Err(error)
+            };
+        }.boxed();
+    }
+
+    /// Writes into a temporary file first and renames it over the entry, so readers and
+    /// crashes only ever see the old or the new entry, never a torn one.
+    fn store(&self, key: &str, data: Vec<u8>) -> BoxFuture<'_, Result<(), io::Error>> {
+        let temporary = self.entry_path(key, &format!(".tmp-{}", uuid::Uuid::new_v4()));
+        let path = self.entry_path(key, ".bin");
+
+        return async move {
+            let result = async {
+                let mut file = File::create(&temporary).await?;
+                file.write_all(&data).await?;
+                file.sync_all().await?;
+                return fs::rename(&temporary, &path).await;
+            }.await;
+
+            if result.is_err() {
+                let _ = fs::remove_file(&temporary).await;
+            }
+
+            return result;
+        }.boxed();
+    }
+
+    fn delete(&self, key: &str) -> BoxFuture<'_, Result<(), io::Error>> {
+        let path = self.entry_path(key, ".bin");
+        return async move { fs::remove_file(path).await }.boxed();
+    }
+
+    fn quarantine(&self, key: &str, _data: Vec<u8>) -> BoxFuture<'_, Result<(), io::Error>> {
+        let path = self.entry_path(key, ".bin");
+        let quarantine = self.root.join("quarantine");
+
+        return async move {
+            fs::create_dir_all(&quarantine).await?;
+            return fs::rename(&path, quarantine.join(path.file_name().unwrap())).await;
+        }.boxed();
+    }
+
+    fn list(&self) -> BoxFuture<'_, Result<Vec<String>, io::Error>> {
+        return async move {
+            let mut dir = fs::read_dir(&self.root).await?;
+            let mut vec: Vec<String> = Vec::new();
+
+            while let Some(file) = dir.next_entry().await? {
+                if file.file_type().await?.is_dir() {
+                    continue;
+                }
+
+                // Partial downloads and temporary files live next to the `.bin` entries.
+                let Some(name) = file.file_name().to_str().and_then(|name| name.strip_suffix(".bin")).map(|name| name.to_string()) else {
+                    continue;
+                };
+
+                if let Ok(result) = BASE64_URL_SAFE.decode(name) && let Ok(key) = String::from_utf8(result) {
+                    vec.push(key);
+                }
+            }
+
+            return Ok(vec);
+        }.boxed();
+    }
+
+    fn stat_blob(&self, name: &str) -> BoxFuture<'_, Result<Option<BlobStat>, io::Error>> {
+        let path = self.blob_path(name);
+
+        return async move {
+            let metadata = match fs::metadata(path).await {
+                Ok(metadata) => metadata,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(error) => return Err(error)
+            };
+
+            let modified = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+            return Ok(Some(BlobStat {
+                length: metadata.len(),
+                version: modified.as_nanos().to_string()
+            }));
+        }.boxed();
+    }
+
+    fn put_blob(&self, name: &str, file: &Path) -> BoxFuture<'_, Result<(), io::Error>> {
+        let path = self.blob_path(name);
+        let file = file.to_path_buf();
+
+        return async move {
+            fs::create_dir_all(path.parent().unwrap()).await?;
+            return fs::rename(file, path).await;
+        }.boxed();
+    }
+
+    fn open_blob(&self, name: &str, offset: u64) -> BoxFuture<'_, Result<BlobStream, io::Error>> {
+        let path = self.blob_path(name);
+
+        return async move {
+            let mut file = File::open(path).await?;
+            file.seek(SeekFrom::Start(offset)).await?;
+
+            let stream = futures::stream::unfold(Some(file), |file| async move {
+                let mut file = file?;
+                let mut buffer = vec![0u8; 64 * 1024];
+
+                return match file.read(&mut buffer).await {
+                    Ok(0) => None,
+                    Ok(read) => {
+                        buffer.truncate(read);
+                        Some((Ok(Bytes::from(buffer)), Some(file)))
+                    },
+                    Err(error) => Some((Err(error), None))
+                };
+            });
+
+            return Ok(stream.boxed());
+        }.boxed();
+    }
+
+    fn delete_blob(&self, name: &str) -> BoxFuture<'_, Result<(), io::Error>> {
+        let path = self.blob_path(name);
+        return async move { fs::remove_file(path).await }.boxed();
+    }
+}
@@ -0,0 +1,54 @@
+use std::{io, path::Path, sync::Arc};
+
+use bytes::Bytes;
+use futures::{future::BoxFuture, stream::BoxStream};
+
+use crate::{config::Config, http::api::store::{fs::FsStore, s3::S3Store}};
+
+pub mod fs;
+pub mod s3;
+
+
+pub type BlobStream = BoxStream<'static, Result<Bytes, io::Error>>;
+
+/// Length of a stored blob plus something that changes whenever the blob is rewritten
+/// (mtime, object etag), used to skip re-hashing blobs that were verified before.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct BlobStat {
+    pub length: u64,
+    pub version: String,
+}
+
+/// Where cache entries (keyed by uri) and tarball blobs (keyed by digest name) are kept.
+/// Entries are opaque bytes here, their format is handled by `entry`.
+pub trait CacheStore: Send + Sync {
+    fn load(&self, key: &str) -> BoxFuture<'_, Result<Option<Vec<u8>>, io::Error>>;
+
+    /// Replaces the entry at once, readers never observe a partially written entry.
+    fn store(&self, key: &str, data: Vec<u8>) -> BoxFuture<'_, Result<(), io::Error>>;
+
+    fn delete(&self, key: &str) -> BoxFuture<'_, Result<(), io::Error>>;
+
+    /// Keeps an unreadable entry aside for inspection and removes it from the cache.
+    fn quarantine(&self, key: &str, data: Vec<u8>) -> BoxFuture<'_, Result<(), io::Error>>;
+
+    fn list(&self) -> BoxFuture<'_, Result<Vec<String>, io::Error>>;
+
+    fn stat_blob(&self, name: &str) -> BoxFuture<'_, Result<Option<BlobStat>, io::Error>>;
+
+    /// Takes ownership of a completely written local file and stores it as `name`.
+    fn put_blob(&self, name: &str, file: &Path) -> BoxFuture<'_, Result<(), io::Error>>;
+
+    /// Streams the blob from byte `offset` on.
+    fn open_blob(&self, name: &str, offset: u64) -> BoxFuture<'_, Result<BlobStream, io::Error>>;
+
+    fn delete_blob(&self, name: &str) -> BoxFuture<'_, Result<(), io::Error>>;
+}
+
+pub fn from_config(config: &Config, cache: &Path) -> Result<Arc<dyn CacheStore>, String> {
+    return match config.cache_store.as_str() {
+        "s3" => Ok(Arc::new(S3Store::new(config)?)),
+        "fs" => Ok(Arc::new(FsStore::new(cache))),
+        other => Err(format!("PROXY_CACHE_STORE {other} is not a cache store, expected fs or s3"))
+    };
+}
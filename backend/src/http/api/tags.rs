@@ -0,0 +1,131 @@
+use std::{collections::{BTreeMap, HashMap}, io, sync::Arc, time::{Duration, Instant}};
+
+use serde_json::Value;
+use tokio::sync::{Mutex, RwLock};
+
+use crate::{config::Config, http::api::{entry, freshness::FreshnessPolicy, storage::ApiStorage, store::CacheStore}};
+
+/// All overlays live in one small entry, every replica reads the same.
+const KEY: &str = "-/dist-tags";
+
+type Overlays = HashMap<String, BTreeMap<String, String>>;
+
+/// Overlay value of a removed tag, it hides the upstream tag of the same name.
+const REMOVED: &str = "";
+
+
+/// Dist-tags set through `npm dist-tag add` on top of the ones upstream publishes, by
+/// package uri. They are kept in memory and re-read from the store once older than the
+/// metadata ttl, so tags set on another replica show up as fast as upstream changes do.
+#[derive(Clone)]
+pub struct DistTags {
+    store: Arc<dyn CacheStore>,
+    ttl: Duration,
+    overlays: Arc<RwLock<(Option<Instant>, Overlays)>>,
+    /// Held while an overlay is read, changed and written back.
+    writing: Arc<Mutex<()>>,
+}
+
+impl DistTags {
+    pub fn new(config: &Config, store: Arc<dyn CacheStore>) -> Self {
+        return Self {
+            store,
+            ttl: Duration::from_secs(config.metadata_ttl),
+            overlays: Arc::new(RwLock::new((None, HashMap::new()))),
+            writing: Arc::new(Mutex::new(()))
+        };
+    }
+
+    pub async fn for_package(&self, uri: &str) -> BTreeMap<String, String> {
+        {
+            let overlays = self.overlays.read().await;
+
+            if overlays.0.is_some_and(|loaded| loaded.elapsed() < self.ttl) {
+                return overlays.1.get(uri).cloned().unwrap_or_default();
+            }
+        }
+
+        return match self.load().await {
+            Ok(overlays) => {
+                let tags = overlays.get(uri).cloned().unwrap_or_default();
+                *self.overlays.write().await = (Some(Instant::now()), overlays);
+                tags
+            },
+            Err(error) => {
+                println!("Could not read dist-tag overlays: {error}");
+                self.overlays.read().await.1.get(uri).cloned().unwrap_or_default()
+            }
+        };
+    }
+
+    /// Points `tag` at `version`, or removes the tag when `version` is `None`. Removals are kept
+    /// as tombstones, the tag may still come from upstream.
+    pub async fn set(&self, uri: &str, tag: &str, version: Option<String>) -> Result<(), io::Error> {
+        let _writing = self.writing.lock().await;
+        let mut overlays = self.load().await?;
+
+        let tags = overlays.entry(uri.to_string()).or_default();
+
+        match version {
+            Some(version) => tags.insert(tag.to_string(), version),
+            None => tags.insert(tag.to_string(), REMOVED.to_string())
+        };
+
+        let stored = ApiStorage {
+            headers: HashMap::from([("content-type".to_string(), b"application/json".to_vec())]),
+            body: serde_json::to_vec(&overlays).unwrap(),
+            stored_at: FreshnessPolicy::now(),
+            integrity: None,
+            stream: None
+        };
+
+        self.store.store(KEY, entry::encode(&stored)).await?;
+        *self.overlays.write().await = (Some(Instant::now()), overlays);
+        return Ok(());
+    }
+
+    /// Overlay tags replace upstream tags of the same name, removed ones drop them.
+    pub fn apply(packument: &mut Value, overlay: &BTreeMap<String, String>) {
+        let Some(object) = packument.as_object_mut() else {
+            return;
+        };
+
+        let Some(tags) = object.entry("dist-tags").or_insert(Value::Object(Default::default())).as_object_mut() else {
+            return;
+        };
+
+        for (tag, version) in overlay {
+            if version == REMOVED {
+                tags.remove(tag);
+            } else {
+                tags.insert(tag.clone(), Value::String(version.clone()));
+            }
+        }
+    }
+
+    async fn load(&self) -> Result<Overlays, io::Error> {
+        let Some(value) = self.store.load(KEY).await? else {
+            return Ok(HashMap::new());
+        };
+
+        let stored = entry::decode(&value).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error.to_string()))?;
+        return serde_json::from_slice(&stored.body).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn overlay_replaces_and_removes_upstream_tags() {
+        let mut packument = json!({ "dist-tags": { "latest": "2.0.0", "beta": "3.0.0-beta.1" } });
+        let overlay = BTreeMap::from([("beta".to_string(), REMOVED.to_string()), ("next".to_string(), "3.0.0".to_string())]);
+
+        DistTags::apply(&mut packument, &overlay);
+
+        assert_eq!(packument["dist-tags"], json!({ "latest": "2.0.0", "next": "3.0.0" }));
+    }
+}
@@ -1,12 +1,13 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 
-use axum::{extract::Query, http::{header::RETRY_AFTER, StatusCode}, response::{AppendHeaders, IntoResponse, Redirect}, routing::{get, post}, Json, Router};
+use axum::{extract::{Path, Query}, http::{header::RETRY_AFTER, StatusCode}, response::{AppendHeaders, IntoResponse, Redirect}, routing::{delete, get, post}, Extension, Json, Router};
 use axum_extra::extract::{cookie::Cookie, CookieJar};
 use redis::{Commands};
 use serde::{Deserialize, Serialize};
-use serde_json::{json};
+use ipnet::IpNet;
+use serde_json::{json, Value};
 
-use crate::http::auth::{authenticator::Authenticator, token::cache::TokenCache};
+use crate::{domain::Tokens::{Scope, TokenRecord}, http::auth::{authenticator::Authenticator, scope::{self, refuse}, token::api::TokenApi}};
 
 #[derive(Deserialize, Serialize, redis_macros::FromRedisValue, redis_macros::ToRedisArgs)]
 enum AuthenticatorStatus {
@@ -15,6 +16,11 @@ enum AuthenticatorStatus {
     Unknown()
 }
 
+fn listing(tokens: Vec<(String, TokenRecord)>) -> Value {
+    let objects: Vec<Value> = tokens.iter().map(|(token, record)| TokenApi::describe(token, record)).collect();
+    return json!({ "objects": objects, "total": objects.len(), "urls": {} });
+}
+
 #[derive(Clone)]
 pub struct AuthenticatorApi {
     self_url: String,
@@ -54,6 +60,7 @@ impl AuthenticatorApi {
             let client = self.redis.clone();
 
             #[derive(Serialize, Deserialize, Clone)]
+            #[allow(non_snake_case)]
             struct LoginResponse {
                 loginUrl: String,
                 doneUrl: String
@@ -66,14 +73,14 @@ impl AuthenticatorApi {
                 let uuid = uuid::Uuid::new_v4();
 
                 if let Ok(mut connection) = client.get_connection() {
-                    let response: Result<AuthenticatorStatus, redis::RedisError> =connection.get(&uuid.to_string());
-                    if let Err(_) = response {
+                    let response: Result<AuthenticatorStatus, redis::RedisError> =connection.get(uuid.to_string());
+                    if response.is_err() {
 
-                        let () = connection.set(&uuid.to_string(), AuthenticatorStatus::Empty()).unwrap();
+                        let () = connection.set(uuid.to_string(), AuthenticatorStatus::Empty()).unwrap();
                         
                         let response = LoginResponse {
-                            loginUrl: (base.clone() + "login?id=" + &urlencoding::encode(&uuid.to_string()).to_string()).to_string(),
-                            doneUrl: (base.clone() + "check_done?id=" + &urlencoding::encode(&uuid.to_string()).to_string()).to_string(),
+                            loginUrl: (base.clone() + "login?id=" + &urlencoding::encode(&uuid.to_string())).to_string(),
+                            doneUrl: (base.clone() + "check_done?id=" + &urlencoding::encode(&uuid.to_string())).to_string(),
                         };
 
                         return Ok(Json(json!(response.clone())));
@@ -93,7 +100,7 @@ impl AuthenticatorApi {
 
                 return (
                     jar.add(Cookie::new("_csrf", token.secret().clone())),
-                    Redirect::temporary(&uri.to_string())
+                    Redirect::temporary(uri.as_ref())
                 );
             }))
         }
@@ -144,13 +151,113 @@ impl AuthenticatorApi {
         {
             let authenticator = self.authenticator.clone();
             resulting_router = resulting_router.route("/ci_token", get(async move |Query(all): Query<HashMap<String, String>>| {
-                let code = all.get("code").unwrap();
+                let Some(code) = all.get("code") else {
+                    return refuse(StatusCode::BAD_REQUEST, "the code of the login is missing");
+                };
 
-                authenticator.get_from_redirected_only_token(code.clone()).await;
-                authenticator.token.cache.temp_token(code.clone()).await;
+                return match authenticator.get_from_redirected_only_token(code.clone()).await {
+                    Some(token) => Json(json!(token)).into_response(),
+                    None => refuse(StatusCode::FORBIDDEN, "the login could not be completed")
+                };
+            }).route_layer(scope::require(Scope::Read)))
+        }
 
-                return Json(json!(code)).into_response();
-            }))
+        {
+            #[derive(Deserialize)]
+            struct CreateTokenRequest {
+                password: Option<String>,
+                #[serde(default)]
+                readonly: bool,
+                #[serde(default)]
+                cidr_whitelist: Vec<String>,
+                /// Defaults to the scopes of the token creating it, without `admin`.
+                scopes: Option<BTreeSet<Scope>>,
+                name: Option<String>,
+            }
+
+            let list_auth = self.authenticator.clone();
+            let create_auth = self.authenticator.clone();
+            let revoke_auth = self.authenticator.clone();
+
+            // `npm token list`, `npm token create` and `npm token revoke`.
+            resulting_router = resulting_router.route("/-/npm/v1/tokens", get(async move |Extension(record): Extension<TokenRecord>| {
+                if record.owner.subject.is_empty() {
+                    return Json(listing(vec![])).into_response();
+                }
+
+                return match list_auth.token.cache.tokens_of(&record.owner.subject).await {
+                    Ok(tokens) => Json(listing(tokens)).into_response(),
+                    Err(error) => refuse(StatusCode::SERVICE_UNAVAILABLE, &error.to_string())
+                };
+            }).post(async move |Extension(record): Extension<TokenRecord>, Json(request): Json<CreateTokenRequest>| {
+                if record.owner.subject.is_empty() {
+                    return refuse(StatusCode::FORBIDDEN, "this token has no owner, log in again with `npm login` first");
+                }
+
+                if let Some(network) = request.cidr_whitelist.iter().find(|network| network.parse::<IpNet>().is_err()) {
+                    return refuse(StatusCode::BAD_REQUEST, &format!("{network} is not a CIDR range"));
+                }
+
+                let mut scopes = request.scopes.unwrap_or_else(|| record.scopes.iter().filter(|scope| **scope != Scope::Admin).copied().collect());
+
+                if request.readonly {
+                    scopes.retain(|scope| *scope == Scope::Read);
+                }
+
+                // A token never gets more than the one creating it.
+                if let Some(scope) = scopes.iter().find(|scope| !record.scopes.contains(scope)) {
+                    return refuse(StatusCode::FORBIDDEN, &format!("this token lacks the {} scope it would grant", scope.name()));
+                }
+
+                if let Err(message) = create_auth.reauthenticate(&record, request.password.as_deref()).await {
+                    return refuse(StatusCode::UNAUTHORIZED, &message);
+                }
+
+                let (token, created) = create_auth.token.create_for(record.owner.clone(), scopes, request.cidr_whitelist, request.name, record.session.clone()).await;
+                let mut response = TokenApi::describe(&token, &created);
+                response["token"] = json!(token);
+
+                return Json(response).into_response();
+            }).route_layer(scope::require(Scope::Read)));
+
+            resulting_router = resulting_router.route("/-/npm/v1/tokens/token/{key}", delete(async move |Extension(record): Extension<TokenRecord>, Path(key): Path<String>| {
+                if record.owner.subject.is_empty() {
+                    return refuse(StatusCode::NOT_FOUND, "no such token");
+                }
+
+                return match revoke_auth.token.revoke(Some(&record.owner.subject), &key).await {
+                    Ok(true) => StatusCode::NO_CONTENT.into_response(),
+                    Ok(false) => refuse(StatusCode::NOT_FOUND, "no such token"),
+                    Err(error) => refuse(StatusCode::SERVICE_UNAVAILABLE, &error.to_string())
+                };
+            }).route_layer(scope::require(Scope::Read)));
+        }
+
+        {
+            let list_auth = self.authenticator.clone();
+            let revoke_auth = self.authenticator.clone();
+
+            // The tokens of every user, for admins. `owner` filters by username or subject.
+            resulting_router = resulting_router.route("/-/api/tokens", get(async move |Query(query): Query<HashMap<String, String>>| {
+                return match list_auth.token.cache.all_tokens().await {
+                    Ok(mut tokens) => {
+                        if let Some(owner) = query.get("owner") {
+                            tokens.retain(|(_, record)| record.owner.subject == *owner || record.owner.username.as_ref() == Some(owner));
+                        }
+
+                        Json(listing(tokens)).into_response()
+                    },
+                    Err(error) => refuse(StatusCode::SERVICE_UNAVAILABLE, &error.to_string())
+                };
+            }).route_layer(scope::require(Scope::Admin)));
+
+            resulting_router = resulting_router.route("/-/api/tokens/{key}", delete(async move |Path(key): Path<String>| {
+                return match revoke_auth.token.revoke(None, &key).await {
+                    Ok(true) => StatusCode::NO_CONTENT.into_response(),
+                    Ok(false) => refuse(StatusCode::NOT_FOUND, "no such token"),
+                    Err(error) => refuse(StatusCode::SERVICE_UNAVAILABLE, &error.to_string())
+                };
+            }).route_layer(scope::require(Scope::Admin)));
         }
 
         return resulting_router;
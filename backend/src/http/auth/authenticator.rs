@@ -1,18 +1,28 @@
-use std::time::Duration;
+use std::{net::{IpAddr, SocketAddr}, time::Duration};
 
-use axum::{extract::Request, http::StatusCode, middleware::Next, response::Response};
-use openidconnect::{core::{CoreAuthenticationFlow, CoreClient, CoreProviderMetadata}, AuthorizationCode, ClientId, ClientSecret, CsrfToken, IssuerUrl, Nonce, OAuth2TokenResponse, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope};
+use axum::{extract::{ConnectInfo, Request}, http::StatusCode, middleware::Next, response::Response};
+use chrono::Utc;
+use openidconnect::{core::{CoreAuthenticationFlow, CoreClient, CoreErrorResponseType, CoreProviderMetadata}, AuthorizationCode, ClientId, ClientSecret, CsrfToken, IssuerUrl, Nonce, OAuth2TokenResponse, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, RefreshToken, RequestTokenError, ResourceOwnerPassword, ResourceOwnerUsername, Scope, TokenResponse};
 use reqwest::{header, Client};
 
-use crate::{config::Config, domain::Tokens::Tokens, http::auth::{token::api::TokenApi}};
+use crate::{config::Config, domain::Tokens::{Identity, Session, TokenRecord, Tokens}, http::auth::{scope, token::api::TokenApi}};
 
+type OidcTokenResponse = openidconnect::StandardTokenResponse<openidconnect::IdTokenFields<openidconnect::EmptyAdditionalClaims, openidconnect::EmptyExtraTokenFields, openidconnect::core::CoreGenderClaim, openidconnect::core::CoreJweContentEncryptionAlgorithm, openidconnect::core::CoreJwsSigningAlgorithm>, openidconnect::core::CoreTokenType>;
+type OidcClient = openidconnect::Client<openidconnect::EmptyAdditionalClaims, openidconnect::core::CoreAuthDisplay, openidconnect::core::CoreGenderClaim, openidconnect::core::CoreJweContentEncryptionAlgorithm, openidconnect::core::CoreJsonWebKey, openidconnect::core::CoreAuthPrompt, openidconnect::StandardErrorResponse<openidconnect::core::CoreErrorResponseType>, openidconnect::StandardTokenResponse<openidconnect::IdTokenFields<openidconnect::EmptyAdditionalClaims, openidconnect::EmptyExtraTokenFields, openidconnect::core::CoreGenderClaim, openidconnect::core::CoreJweContentEncryptionAlgorithm, openidconnect::core::CoreJwsSigningAlgorithm>, openidconnect::core::CoreTokenType>, openidconnect::StandardTokenIntrospectionResponse<openidconnect::EmptyExtraTokenFields, openidconnect::core::CoreTokenType>, openidconnect::core::CoreRevocableToken, openidconnect::StandardErrorResponse<openidconnect::RevocationErrorResponseType>, openidconnect::EndpointSet, openidconnect::EndpointNotSet, openidconnect::EndpointNotSet, openidconnect::EndpointNotSet, openidconnect::EndpointMaybeSet, openidconnect::EndpointMaybeSet>;
 
 #[derive(Clone)]
 pub struct Authenticator {
     pub token: TokenApi,
     http_client: Client,
-    client:  openidconnect::Client<openidconnect::EmptyAdditionalClaims, openidconnect::core::CoreAuthDisplay, openidconnect::core::CoreGenderClaim, openidconnect::core::CoreJweContentEncryptionAlgorithm, openidconnect::core::CoreJsonWebKey, openidconnect::core::CoreAuthPrompt, openidconnect::StandardErrorResponse<openidconnect::core::CoreErrorResponseType>, openidconnect::StandardTokenResponse<openidconnect::IdTokenFields<openidconnect::EmptyAdditionalClaims, openidconnect::EmptyExtraTokenFields, openidconnect::core::CoreGenderClaim, openidconnect::core::CoreJweContentEncryptionAlgorithm, openidconnect::core::CoreJwsSigningAlgorithm>, openidconnect::core::CoreTokenType>, openidconnect::StandardTokenIntrospectionResponse<openidconnect::EmptyExtraTokenFields, openidconnect::core::CoreTokenType>, openidconnect::core::CoreRevocableToken, openidconnect::StandardErrorResponse<openidconnect::RevocationErrorResponseType>, openidconnect::EndpointSet, openidconnect::EndpointNotSet, openidconnect::EndpointNotSet, openidconnect::EndpointNotSet, openidconnect::EndpointMaybeSet, openidconnect::EndpointMaybeSet>,
-    self_url: String
+    client:  OidcClient,
+    #[allow(dead_code)]
+    self_url: String,
+    /// How long after a login its token may create tokens without a password.
+    reauth_window: Duration,
+    /// Header a reverse proxy puts the client address in, the peer address is used without one.
+    client_ip_header: Option<String>,
+    /// How often the OIDC tokens of a login are refreshed, never when zero.
+    session_refresh: Duration
 }
 
 impl Authenticator {
@@ -32,16 +42,111 @@ impl Authenticator {
         )
         .set_redirect_uri(RedirectUrl::new(config.self_url.clone()).unwrap());
 
-        return Authenticator {
-            http_client:  http_client,
-            client: client,
+        let authenticator = Authenticator {
+            http_client,
+            client,
             self_url: config.self_url.clone(),
-            token: TokenApi::new(redis, duration).await
+            reauth_window: Duration::from_secs(config.token_reauth_window),
+            client_ip_header: config.client_ip_header.clone(),
+            session_refresh: Duration::from_secs(config.session_refresh),
+            token: TokenApi::new(redis, duration, config.token_secret.clone(), Duration::from_secs(config.token_ttl), config.admins.clone()).await
+        };
+
+        if !authenticator.session_refresh.is_zero() {
+            let authenticator = authenticator.clone();
+            tokio::spawn(async move {
+                // Sessions are checked more often than they are refreshed, so none waits much
+                // longer than the refresh interval.
+                let mut interval = tokio::time::interval((authenticator.session_refresh / 4).max(Duration::from_secs(1)));
+                loop {
+                    interval.tick().await;
+                    authenticator.refresh_sessions().await;
+                }
+            });
+        }
+
+        return authenticator;
+    }
+
+    async fn refresh_sessions(&self) {
+        let due = match self.token.sessions.due(self.session_refresh) {
+            Ok(due) => due,
+            Err(error) => {
+                println!("Could not list the sessions to refresh: {error}");
+                return;
+            }
+        };
+
+        for (id, session) in due {
+            match self.token.sessions.claim(&id, self.session_refresh) {
+                Ok(true) => self.refresh_session(&id, session).await,
+                Ok(false) => {},
+                Err(error) => println!("Could not claim session {id}: {error}")
+            }
+        }
+    }
+
+    /// Refreshes the OIDC tokens of a login. When the identity provider refuses, the user was
+    /// removed or lost access, and every npm token from the login is revoked with it.
+    async fn refresh_session(&self, id: &str, mut session: Session) {
+        let tokens = match self.token.cache.tokens_of(&session.subject).await {
+            Ok(tokens) => tokens.into_iter().filter(|(_, record)| record.session.as_deref() == Some(id)).collect::<Vec<_>>(),
+            Err(error) => {
+                println!("Could not list the tokens of session {id}: {error}");
+                return;
+            }
+        };
+
+        // Its tokens were revoked or expired, there is nothing left to keep alive.
+        if tokens.is_empty() {
+            if let Err(error) = self.token.sessions.end(id) {
+                println!("Could not end session {id}: {error}");
+            }
+            return;
+        }
+
+        let refresh_token = RefreshToken::new(session.refresh_token.clone());
+        let request = match self.client.exchange_refresh_token(&refresh_token) {
+            Ok(request) => request,
+            Err(error) => {
+                println!("Could not refresh session {id}: {error}");
+                return;
+            }
+        };
+
+        match request.request_async(&self.http_client).await {
+            Ok(response) => {
+                session.access_token = response.access_token().secret().to_string();
+                if let Some(refresh_token) = response.refresh_token() {
+                    session.refresh_token = refresh_token.secret().to_string();
+                }
+                session.refreshed = Utc::now().timestamp();
+
+                if let Err(error) = self.token.sessions.store(id, &session) {
+                    println!("Could not store the refreshed session {id}: {error}");
+                }
+            },
+            Err(RequestTokenError::ServerResponse(response)) if *response.error() == CoreErrorResponseType::InvalidGrant => {
+                for (hash, record) in &tokens {
+                    if let Err(error) = self.token.cache.revoke(hash, record).await {
+                        println!("Could not revoke a token of session {id}: {error}");
+                        return;
+                    }
+                }
+
+                if let Err(error) = self.token.sessions.end(id) {
+                    println!("Could not end session {id}: {error}");
+                }
+
+                println!("The identity provider ended the login of {}, revoked {} tokens", session.subject, tokens.len());
+            },
+            // Maybe the identity provider is down, the tokens stay until it refuses.
+            Err(error) => println!("Could not refresh session {id}, trying again later: {error}")
         }
     }
     
     pub fn get_redirect_url(&self, state: String) -> (PkceCodeVerifier, (reqwest::Url, CsrfToken, Nonce)) {
-        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+        let (_pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
 
         return (pkce_verifier, self.client
             .authorize_url(
@@ -52,12 +157,13 @@ impl Authenticator {
             // Set the desired scopes.
             .add_scope(Scope::new("openid".to_string()))
             .add_scope(Scope::new("profile".to_string()))
+            .add_scope(Scope::new("email".to_string()))
             // Set the PKCE code challenge.
             //.set_pkce_challenge(pkce_challenge)
             .url());
     }
 
-    pub async fn get_from_redirected_only_token(&self, token: String) -> String {
+    pub async fn get_from_redirected_only_token(&self, token: String) -> Option<String> {
 
         // let pkce_verifier = PkceCodeVerifier::new(csrf);
 
@@ -67,10 +173,13 @@ impl Authenticator {
         // .set_pkce_verifier(pkce_verifier)
         .request_async(&self.http_client).await.unwrap();
 
-        return self.token.create_token(Tokens { refresh_token: response.refresh_token().unwrap().secret().to_string().clone(), access_token: response.access_token().secret().to_string().clone() }).await;
+        // Without a verified owner the token could not be checked against its owner later.
+        let identity = self.identity(&response)?;
+
+        return self.token.create_token(Tokens { refresh_token: response.refresh_token().unwrap().secret().to_string().clone(), access_token: response.access_token().secret().to_string().clone(), identity }).await;
     }
 
-    pub async fn get_from_redirected(&self, token: String, csrf: String) -> String {
+    pub async fn get_from_redirected(&self, token: String, _csrf: String) -> Option<String> {
 
         // let pkce_verifier = PkceCodeVerifier::new(csrf);
 
@@ -80,14 +189,74 @@ impl Authenticator {
         // .set_pkce_verifier(pkce_verifier)
         .request_async(&self.http_client).await.unwrap();
 
-        return self.token.create_token(Tokens { refresh_token: response.refresh_token().unwrap().secret().to_string().clone(), access_token: response.access_token().secret().to_string().clone() }).await;
+        // Without a verified owner the token could not be checked against its owner later.
+        let identity = self.identity(&response)?;
+
+        return self.token.create_token(Tokens { refresh_token: response.refresh_token().unwrap().secret().to_string().clone(), access_token: response.access_token().secret().to_string().clone(), identity }).await;
     }
 
-    async fn authorize(&self, str: &str) -> bool {
+    /// The signed in user from the ID token of a code exchange. The nonce is not checked, the
+    /// token comes straight from the token endpoint and the login flow does not keep one.
+    fn identity(&self, response: &OidcTokenResponse) -> Option<Identity> {
+        let Some(id_token) = response.id_token() else {
+            println!("The identity provider sent no ID token");
+            return None;
+        };
+
+        return match id_token.claims(&self.client.id_token_verifier(), |_: Option<&Nonce>| Ok(())) {
+            Ok(claims) => Some(Identity {
+                subject: claims.subject().to_string(),
+                username: claims.preferred_username().map(|username| username.to_string()),
+                email: claims.email().map(|email| email.to_string())
+            }).filter(|identity| !identity.subject.is_empty()),
+            Err(error) => {
+                println!("Could not verify the ID token: {error}");
+                None
+            }
+        };
+    }
+
+    /// Makes sure the owner of a token is at the keyboard before it is used to create another
+    /// one. Either the password is accepted by the identity provider for the owner, or the
+    /// token comes from a login within the re-authentication window.
+    pub async fn reauthenticate(&self, record: &TokenRecord, password: Option<&str>) -> Result<(), String> {
+        let Some(password) = password.filter(|password| !password.is_empty()) else {
+            let age = Utc::now().timestamp() - record.created;
+
+            if record.from_login && age <= self.reauth_window.as_secs() as i64 {
+                return Ok(());
+            }
+
+            return Err("a password is required, or log in again with `npm login` first".to_string());
+        };
+
+        let Some(username) = &record.owner.username else {
+            return Err("the token has no username to check the password for, log in again with `npm login` first".to_string());
+        };
+
+        let username = ResourceOwnerUsername::new(username.clone());
+        let password = ResourceOwnerPassword::new(password.to_string());
+
+        let response = self.client
+            .exchange_password(&username, &password).map_err(|error| error.to_string())?
+            .add_scope(Scope::new("openid".to_string()))
+            .request_async(&self.http_client).await
+            .map_err(|_| "incorrect password".to_string())?;
+
+        if self.identity(&response).is_none_or(|identity| identity.subject != record.owner.subject) {
+            return Err("the password belongs to another user".to_string());
+        }
+
+        return Ok(());
+    }
+
+    async fn authorize(&self, str: &str) -> Option<TokenRecord> {
         return self.token.verify_token(str.to_string()).await;
     }
 
-    pub async fn middleware(&self, req: Request, next: Next) -> Result<Response, StatusCode> {
+    /// Lets requests with a valid token through, handlers find its `TokenRecord` in the
+    /// request extensions.
+    pub async fn middleware(&self, mut req: Request, next: Next) -> Result<Response, StatusCode> {
         let mut auth_header = req
                 .headers()
                 .get(header::AUTHORIZATION)
@@ -101,10 +270,32 @@ impl Authenticator {
 
             auth_header = auth_header.trim().to_string();
             
-            if  self.authorize(&auth_header).await {
+            if let Some(record) = self.authorize(&auth_header).await {
+                if !record.cidr_whitelist.is_empty() {
+                    match self.client_address(&req) {
+                        Some(address) if record.allows_address(address) => {},
+                        Some(address) => return Ok(scope::refuse(StatusCode::FORBIDDEN, &format!("this token may not be used from {address}"))),
+                        None => return Ok(scope::refuse(StatusCode::FORBIDDEN, "this token is limited to some networks and the client address is unknown"))
+                    }
+                }
+
+                req.extensions_mut().insert(record);
                 return Ok(next.run(req).await);
             }
 
         return Err(StatusCode::UNAUTHORIZED);
     }
+
+    /// The address of the client. Behind a reverse proxy it is the last one in the configured
+    /// header, the one the nearest proxy saw, earlier entries are up to the client.
+    fn client_address(&self, req: &Request) -> Option<IpAddr> {
+        if let Some(header) = &self.client_ip_header {
+            return req.headers().get(header)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.rsplit(',').next())
+                .and_then(|address| address.trim().parse().ok());
+        }
+
+        return req.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(address)| address.ip());
+    }
 }
\ No newline at end of file
@@ -1,4 +1,5 @@
 
 pub mod api;
 pub mod authenticator;
+pub mod scope;
 pub mod token;
\ No newline at end of file
@@ -0,0 +1,35 @@
+use std::pin::Pin;
+
+use axum::{extract::{Request, State}, http::StatusCode, middleware::{self, FromFnLayer, Next}, response::{IntoResponse, Response}, Json};
+use serde_json::json;
+
+use crate::domain::Tokens::{Scope, TokenRecord};
+
+type Check = fn(State<Scope>, Request, Next) -> Pin<Box<dyn Future<Output = Response> + Send>>;
+
+
+/// Answers like the npm registry, `{"error": "..."}`.
+pub fn refuse(status: StatusCode, message: &str) -> Response {
+    return (status, Json(json!({ "error": message }))).into_response();
+}
+
+/// What a route needs of the token, declared next to it with
+/// `.route_layer(scope::require(Scope::Publish))`. Runs after `Authenticator::middleware`,
+/// which leaves the token's record in the request extensions.
+pub fn require(scope: Scope) -> FromFnLayer<Check, Scope, (State<Scope>, Request)> {
+    return middleware::from_fn_with_state(scope, check as Check);
+}
+
+fn check(State(scope): State<Scope>, request: Request, next: Next) -> Pin<Box<dyn Future<Output = Response> + Send>> {
+    return Box::pin(async move {
+        let Some(record) = request.extensions().get::<TokenRecord>() else {
+            return refuse(StatusCode::UNAUTHORIZED, "authentication required");
+        };
+
+        if !record.scopes.contains(&scope) {
+            return refuse(StatusCode::FORBIDDEN, &format!("this token lacks the {} scope", scope.name()));
+        }
+
+        return next.run(request).await;
+    });
+}
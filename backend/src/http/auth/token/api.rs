@@ -1,33 +1,120 @@
-use std::{sync::Arc, time::Duration};
+use std::{collections::BTreeSet, sync::Arc, time::Duration};
 
+use chrono::{DateTime, Utc};
 use rand::{distr::Alphanumeric, rng, Rng};
+use serde_json::{json, Value};
 
-use crate::{domain::Tokens::Tokens, http::auth::token::cache::TokenCache};
+use crate::{domain::Tokens::{Identity, Scope, TokenRecord, Tokens}, http::auth::token::{cache::TokenCache, session::Sessions}};
+
+/// Random characters of a token after `veto-np_`, about 238 bits.
+const TOKEN_LENGTH: usize = 40;
 
 
 #[derive(Clone)]
 pub struct TokenApi {
     pub cache: Arc<TokenCache>,
+    pub sessions: Sessions,
+    /// How long new tokens are valid, forever when zero.
+    ttl: Duration,
+    /// Usernames or subjects whose logins get the admin scope.
+    admins: Vec<String>,
 }
 
 impl TokenApi {
 
-    pub async fn new(redis: redis::Client, duration: Duration) -> Self {
-        return Self { cache: TokenCache::new(redis, duration).await };
+    pub async fn new(redis: redis::Client, duration: Duration, secret: String, ttl: Duration, admins: Vec<String>) -> Self {
+        return Self { sessions: Sessions::new(redis.clone(), &secret), cache: TokenCache::new(redis, duration, secret).await, ttl, admins };
     }
 
-    pub async fn create_token(&self, _token: Tokens) -> String {
+    fn generate() -> String {
         let mut token: String = rng()
         .sample_iter(&Alphanumeric)
-        .take(14)
+        .take(TOKEN_LENGTH)
         .map(char::from)
         .collect();
         token.insert_str(0, "veto-np_");
-        self.cache.store_token_for_user(token.clone()).await;
         return token;
     }
 
-    pub async fn verify_token(&self, token: String) -> bool {
+    /// A token for a login, none when its session cannot be kept.
+    pub async fn create_token(&self, tokens: Tokens) -> Option<String> {
+        let mut scopes = BTreeSet::from([Scope::Read, Scope::Publish]);
+
+        if self.is_admin(&tokens.identity) {
+            scopes.insert(Scope::Admin);
+        }
+
+        let session = match self.sessions.start(&tokens) {
+            Ok(session) => session,
+            Err(error) => {
+                println!("Could not store the session of {}: {error}", tokens.identity.subject);
+                return None;
+            }
+        };
+
+        return Some(self.issue(tokens.identity, scopes, vec![], None, Some(session), true).await.0);
+    }
+
+    /// A token of `owner` limited to `scopes` and networks, e.g. one made with `npm token create`.
+    /// It lives as long as the login `session` of the token creating it.
+    pub async fn create_for(&self, owner: Identity, scopes: BTreeSet<Scope>, cidr_whitelist: Vec<String>, name: Option<String>, session: Option<String>) -> (String, TokenRecord) {
+        return self.issue(owner, scopes, cidr_whitelist, name, session, false).await;
+    }
+
+    async fn issue(&self, owner: Identity, scopes: BTreeSet<Scope>, cidr_whitelist: Vec<String>, name: Option<String>, session: Option<String>, from_login: bool) -> (String, TokenRecord) {
+        let token = TokenApi::generate();
+        let now = Utc::now().timestamp();
+        let record = TokenRecord {
+            owner,
+            prefix: TokenCache::prefix(&token),
+            name,
+            from_login,
+            session,
+            scopes,
+            cidr_whitelist,
+            created: now,
+            last_used: now,
+            expires: Some(now + self.ttl.as_secs() as i64).filter(|_| !self.ttl.is_zero())
+        };
+
+        self.cache.store_token_for_user(token.clone(), record.clone()).await;
+        return (token, record);
+    }
+
+    fn is_admin(&self, identity: &Identity) -> bool {
+        return self.admins.iter().any(|admin| *admin == identity.subject || Some(admin) == identity.username.as_ref());
+    }
+
+    pub async fn verify_token(&self, token: String) -> Option<TokenRecord> {
         return self.cache.get_token_for_user(token).await;
     }
+
+    /// A token as `npm token list` shows it, by the hash it is stored under.
+    pub fn describe(hash: &str, record: &TokenRecord) -> Value {
+        let time = |timestamp: i64| DateTime::from_timestamp(timestamp, 0).map(|time| time.to_rfc3339());
+
+        return json!({
+            "token": record.prefix,
+            "key": hash,
+            "name": record.name,
+            "readonly": !record.scopes.contains(&Scope::Publish),
+            "scopes": record.scopes,
+            "cidr_whitelist": Some(&record.cidr_whitelist).filter(|networks| !networks.is_empty()),
+            "created": time(record.created),
+            "updated": time(record.last_used),
+            "expires": record.expires.and_then(time),
+            "owner": record.owner
+        });
+    }
+
+    /// Revokes the token with the given key, if it belongs to `subject` or to anyone without
+    /// one. Whether there was such a token.
+    pub async fn revoke(&self, subject: Option<&str>, key: &str) -> Result<bool, redis::RedisError> {
+        let Some(record) = self.cache.find(key).await?.filter(|record| subject.is_none_or(|subject| record.owner.subject == subject)) else {
+            return Ok(false);
+        };
+
+        self.cache.revoke(key, &record).await?;
+        return Ok(true);
+    }
 }
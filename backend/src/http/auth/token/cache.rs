@@ -1,69 +1,376 @@
-use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
+use std::{collections::{HashMap, HashSet}, sync::Arc, time::{Duration, Instant}};
 
+use chrono::Utc;
+use futures::StreamExt;
+use hmac::{Hmac, Mac};
 use redis::Commands;
+use sha2::Sha256;
 use tokio::sync::RwLock;
 
-use crate::http::auth::token;
+use crate::domain::Tokens::TokenRecord;
+
+/// Revoked tokens are announced here, every replica drops them from its cache.
+const REVOCATIONS: &str = "token-revocations";
+
+/// When a token was last used is kept apart from its record under this prefix, so reading
+/// a token never writes its record back over a revocation or a change.
+const LAST_USED: &str = "last-used.";
+
+/// Characters of a token kept in its record and shown when listing it.
+const SHOWN_PREFIX: usize = 12;
 
 
 #[derive(Clone)]
 pub struct TokenCache {
     redis: redis::Client,
-    cached: Arc<RwLock<HashMap<String, Instant>>>,
-    cache_duration: Duration
+    /// Records of recently used tokens with when they were read from Redis.
+    /// Keyed by the hash of the token, like Redis.
+    cached: Arc<RwLock<HashMap<String, (Instant, TokenRecord)>>>,
+    cache_duration: Duration,
+    /// Key of the HMAC tokens are stored under, Redis never sees a token itself.
+    secret: Vec<u8>
 }
 
 impl TokenCache {
 
-    pub async fn new(redis: redis::Client, cache_duration: Duration) -> Arc<Self> {
+    pub async fn new(redis: redis::Client, cache_duration: Duration, secret: String) -> Arc<Self> {
         let element = Arc::new(Self{
             cache_duration,
             cached: Arc::new(RwLock::new(HashMap::new())),
-            redis
+            redis,
+            secret: secret.into_bytes()
+        });
+
+        let element_clone = Arc::clone(&element);
+        tokio::spawn(async move {
+            match element_clone.migrate_all() {
+                Ok(0) => {},
+                Ok(count) => println!("Moved {count} tokens stored in plain text to hashed keys"),
+                Err(error) => println!("Could not move tokens stored in plain text, they are moved when used: {error}")
+            }
         });
 
-         let element_clone = Arc::clone(&element);
+        let element_clone = Arc::clone(&element);
         tokio::spawn(async move {
-            element_clone.cleanup().await;
-            tokio::time::interval(cache_duration.clone()).tick().await
+            let mut interval = tokio::time::interval(cache_duration);
+            loop {
+                interval.tick().await;
+                element_clone.cleanup().await;
+            }
+        });
+
+        let element_clone = Arc::clone(&element);
+        tokio::spawn(async move {
+            loop {
+                if let Err(error) = element_clone.follow_revocations().await {
+                    println!("Lost the token revocation channel: {error}");
+                }
+
+                // A revocation missed while reconnecting is picked up when the entry expires.
+                element_clone.cached.write().await.clear();
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
         });
 
         return element;
     }
 
-    pub async fn temp_token(&self, token: String) {
-        self.cached.write().await.insert("token".to_string() + &token, Instant::now());
+    /// What a token is stored under, also its id in `npm token list`.
+    pub fn hash(&self, token: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).unwrap();
+        mac.update(token.as_bytes());
+        return hex::encode(mac.finalize().into_bytes());
+    }
+
+    pub fn is_hash(name: &str) -> bool {
+        return name.len() == 64 && name.bytes().all(|byte| byte.is_ascii_hexdigit());
+    }
+
+    pub fn prefix(token: &str) -> String {
+        return token.chars().take(SHOWN_PREFIX).collect();
+    }
+
+    /// The record of a token that exists and has not expired. `last_used` is written
+    /// whenever the record is read from Redis, so it is accurate to the cache duration.
+    pub async fn get_token_for_user(&self, token_to_check: String) -> Option<TokenRecord> {
+        let now = Utc::now().timestamp();
+        let hash = self.hash(&token_to_check);
+
+        if let Some((_, record)) = self.cached.read().await.get(&hash) {
+            return Some(record.clone()).filter(|record| !record.is_expired(now));
+        }
+
+        let mut connection = self.redis.get_connection().ok()?;
+
+        // Written in plain text by an older replica, possibly after the migration at startup.
+        let mut record = match self.record(&mut connection, &hash).ok()? {
+            Some(record) => record,
+            None => self.migrate(&mut connection, &token_to_check).ok()??.1
+        };
+
+        if record.is_expired(now) {
+            return None;
+        }
+
+        record.last_used = now;
+
+        let written: Result<(), redis::RedisError> = connection.set(LAST_USED.to_string() + &hash, now);
+
+        if let Err(error) = written {
+            println!("Could not update the last use of a token: {error}");
+        }
+
+        self.cached.write().await.insert(hash, (Instant::now(), record.clone()));
+        return Some(record);
+    }
+
+    pub async fn store_token_for_user(&self, token_to_check: String, record: TokenRecord) {
+        let hash = self.hash(&token_to_check);
+        let mut connection = self.redis.get_connection().unwrap();
+        let () = connection.set("token.".to_string() + &hash, serde_json::to_string(&record).unwrap()).unwrap();
+
+        if !record.owner.subject.is_empty() {
+            let _: usize = connection.sadd("tokens.".to_string() + &record.owner.subject, &hash).unwrap();
+        }
+
+        self.cached.write().await.insert(hash, (Instant::now(), record));
+    }
+
+    /// The record stored under a hash, expired or not.
+    fn record(&self, connection: &mut redis::Connection, hash: &str) -> Result<Option<TokenRecord>, redis::RedisError> {
+        let stored: Option<String> = connection.get("token.".to_string() + hash)?;
+        let Some(mut record) = stored.map(|stored| serde_json::from_str::<TokenRecord>(&stored).unwrap_or_else(|_| TokenRecord::legacy())) else {
+            return Ok(None);
+        };
+
+        if let Some(last_used) = connection.get::<_, Option<i64>>(LAST_USED.to_string() + hash)? {
+            record.last_used = last_used;
+        }
+
+        return Ok(Some(record));
     }
 
-    pub async fn get_token_for_user(&self, token_to_check: String) -> bool {
-        let mut exists = self.cached.read().await.contains_key(&token_to_check);
+    /// Moves a token stored under its plain text to its hash, along with its entry in the
+    /// index of its owner. Its hash and record, nothing when there is no such token.
+    fn migrate(&self, connection: &mut redis::Connection, token: &str) -> Result<Option<(String, TokenRecord)>, redis::RedisError> {
+        // A hash sent as a token must not be taken for one stored in plain text.
+        if TokenCache::is_hash(token) {
+            return Ok(None);
+        }
 
-        if !exists {
-            exists = self.redis.get_connection().unwrap().get("token.".to_string() + &token_to_check).unwrap_or(false);
-            if exists {
-                self.cached.write().await.insert(token_to_check.clone(), Instant::now());
+        let stored: Option<String> = connection.get("token.".to_string() + token)?;
+        let Some(stored) = stored else {
+            return Ok(None);
+        };
+
+        let hash = self.hash(token);
+        let mut record = serde_json::from_str::<TokenRecord>(&stored).unwrap_or_else(|_| TokenRecord::legacy());
+        record.prefix = TokenCache::prefix(token);
+
+        let () = connection.set("token.".to_string() + &hash, serde_json::to_string(&record).unwrap())?;
+        let _: usize = connection.del("token.".to_string() + token)?;
+
+        if !record.owner.subject.is_empty() {
+            let index = "tokens.".to_string() + &record.owner.subject;
+            let _: usize = connection.sadd(&index, &hash)?;
+            let _: usize = connection.srem(&index, token)?;
+        }
+
+        return Ok(Some((hash, record)));
+    }
+
+    /// Moves every token still stored in plain text. How many there were.
+    fn migrate_all(&self) -> Result<usize, redis::RedisError> {
+        let mut connection = self.redis.get_connection()?;
+        let keys: Vec<String> = connection.scan_match::<_, String>("token.*")?.collect();
+        let mut count = 0;
+
+        for token in keys.iter().map(|key| key.strip_prefix("token.").unwrap()).filter(|name| !TokenCache::is_hash(name)) {
+            if self.migrate(&mut connection, token)?.is_some() {
+                count += 1;
             }
-        } else {
-            self.cached.write().await.insert(token_to_check.clone(), Instant::now());
         }
 
-        return exists;
+        return Ok(count);
     }
 
-    pub async fn store_token_for_user(&self, token_to_check: String) {
-        let () = self.redis.get_connection().unwrap().set("token.".to_string() + &token_to_check, true).unwrap();
-        self.cached.write().await.insert(token_to_check, Instant::now());
+    /// The record under an index entry or a key, which are hashes unless left from before
+    /// tokens were hashed.
+    fn entry(&self, connection: &mut redis::Connection, name: &str) -> Result<Option<(String, TokenRecord)>, redis::RedisError> {
+        if !TokenCache::is_hash(name) {
+            return self.migrate(connection, name);
+        }
+
+        return Ok(self.record(connection, name)?.map(|record| (name.to_string(), record)));
+    }
+
+    /// The live record of the token with the given hash.
+    pub async fn find(&self, hash: &str) -> Result<Option<TokenRecord>, redis::RedisError> {
+        if !TokenCache::is_hash(hash) {
+            return Ok(None);
+        }
+
+        let mut connection = self.redis.get_connection()?;
+        let now = Utc::now().timestamp();
+        return Ok(self.record(&mut connection, hash)?.filter(|record| !record.is_expired(now)));
+    }
+
+    /// The live tokens of a user by hash, from the per user index. Index entries of tokens that
+    /// expired or are gone are dropped on the way.
+    pub async fn tokens_of(&self, subject: &str) -> Result<Vec<(String, TokenRecord)>, redis::RedisError> {
+        let mut connection = self.redis.get_connection()?;
+        let index = "tokens.".to_string() + subject;
+        let tokens: HashSet<String> = connection.smembers(&index)?;
+        let now = Utc::now().timestamp();
+        let mut found = Vec::new();
+
+        for name in tokens {
+            match self.entry(&mut connection, &name)?.filter(|(_, record)| !record.is_expired(now)) {
+                Some(entry) => found.push(entry),
+                None => {
+                    let _: usize = connection.srem(&index, &name)?;
+                }
+            }
+        }
+
+        return Ok(found);
+    }
+
+    /// Every stored token by hash, legacy ones without an owner included.
+    pub async fn all_tokens(&self) -> Result<Vec<(String, TokenRecord)>, redis::RedisError> {
+        let mut connection = self.redis.get_connection()?;
+        let keys: Vec<String> = connection.scan_match::<_, String>("token.*")?.collect();
+        let now = Utc::now().timestamp();
+        let mut found = Vec::new();
+
+        for key in keys {
+            if let Some((hash, record)) = self.entry(&mut connection, key.strip_prefix("token.").unwrap())? && !record.is_expired(now) {
+                found.push((hash, record));
+            }
+        }
+
+        return Ok(found);
+    }
+
+    /// Deletes the token with the given hash and tells every replica to forget it.
+    pub async fn revoke(&self, hash: &str, record: &TokenRecord) -> Result<(), redis::RedisError> {
+        let mut connection = self.redis.get_connection()?;
+        let _: usize = connection.del(&["token.".to_string() + hash, LAST_USED.to_string() + hash])?;
+
+        if !record.owner.subject.is_empty() {
+            let _: usize = connection.srem("tokens.".to_string() + &record.owner.subject, hash)?;
+        }
+
+        self.cached.write().await.remove(hash);
+        let _: usize = connection.publish(REVOCATIONS, hash)?;
+        return Ok(());
+    }
+
+    async fn follow_revocations(&self) -> Result<(), redis::RedisError> {
+        let mut pubsub = self.redis.get_async_pubsub().await?;
+        pubsub.subscribe(REVOCATIONS).await?;
+        let mut messages = pubsub.on_message();
+
+        while let Some(message) = messages.next().await {
+            let hash: String = message.get_payload()?;
+            self.cached.write().await.remove(&hash);
+        }
+
+        return Ok(());
     }
 
     pub async fn cleanup(&self) {
         let cache_duration = self.cache_duration;
-        let mut map =self.cached.write().await;
-        let to_remove = map.clone().into_iter().filter(|(_, k)| {
-            return k.elapsed() > cache_duration
-        });
+        self.cached.write().await.retain(|_, (cached_at, _)| cached_at.elapsed() <= cache_duration);
+    }
+}
 
-        for entry in to_remove {
-            map.remove(&entry.0);
-        }
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use rand::{distr::Alphanumeric, rng, Rng};
+
+    use crate::domain::Tokens::Identity;
+
+    use super::*;
+
+    fn cache(secret: &str) -> TokenCache {
+        let redis = redis::Client::open(env::var("REDIS_URI").unwrap_or("redis://localhost:6379".to_string())).unwrap();
+
+        return TokenCache {
+            redis,
+            cached: Arc::new(RwLock::new(HashMap::new())),
+            cache_duration: Duration::from_secs(60),
+            secret: secret.as_bytes().to_vec()
+        };
+    }
+
+    fn random() -> String {
+        return rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect();
+    }
+
+    #[test]
+    fn recognises_hashes() {
+        let hash = cache("secret").hash("veto-npm-token");
+
+        assert!(TokenCache::is_hash(&hash));
+        assert!(TokenCache::is_hash(&hash.to_uppercase()));
+        assert!(!TokenCache::is_hash(&hash[1..]));
+        assert!(!TokenCache::is_hash(&(hash.clone() + "0")));
+        assert!(!TokenCache::is_hash(&("g".to_string() + &hash[1..])));
+        assert!(!TokenCache::is_hash("veto-npm-token"));
+    }
+
+    #[test]
+    fn hashes_with_the_secret() {
+        assert_eq!(cache("secret").hash("veto-npm-token"), cache("secret").hash("veto-npm-token"));
+        assert_ne!(cache("secret").hash("veto-npm-token"), cache("other").hash("veto-npm-token"));
+        assert_ne!(cache("secret").hash("veto-npm-token"), cache("secret").hash("veto-npm-token2"));
+        assert_eq!(TokenCache::prefix("veto-npm-0123456789"), "veto-npm-012");
     }
-}
\ No newline at end of file
+
+    /// Needs a Redis at `REDIS_URI`, keys are random and removed again.
+    #[tokio::test]
+    #[ignore]
+    async fn migrates_plain_tokens_once_used() {
+        let cache = cache("secret");
+        let mut connection = cache.redis.get_connection().unwrap();
+
+        let subject = "test-".to_string() + &random();
+        let token = random();
+        let mut record = TokenRecord::legacy();
+        record.owner = Identity { subject: subject.clone(), ..Default::default() };
+
+        let () = connection.set("token.".to_string() + &token, serde_json::to_string(&record).unwrap()).unwrap();
+        let _: usize = connection.sadd("tokens.".to_string() + &subject, &token).unwrap();
+
+        let found = cache.get_token_for_user(token.clone()).await.unwrap();
+        let hash = cache.hash(&token);
+
+        assert_eq!(found.prefix, TokenCache::prefix(&token));
+        assert_eq!(connection.get::<_, Option<String>>("token.".to_string() + &token).unwrap(), None);
+        assert!(connection.get::<_, Option<String>>("token.".to_string() + &hash).unwrap().is_some());
+        assert_eq!(connection.smembers::<_, Vec<String>>("tokens.".to_string() + &subject).unwrap(), vec![hash.clone()]);
+
+        let _: usize = connection.del(vec!["token.".to_string() + &hash, LAST_USED.to_string() + &hash, "tokens.".to_string() + &subject]).unwrap();
+    }
+
+    /// Needs a Redis at `REDIS_URI`. Someone who learned a hash, from `npm token list` say,
+    /// must not be able to use it as the token.
+    #[tokio::test]
+    #[ignore]
+    async fn hashes_are_not_taken_for_plain_tokens() {
+        let cache = cache("secret");
+        let mut connection = cache.redis.get_connection().unwrap();
+
+        let hash = cache.hash(&random());
+        let () = connection.set("token.".to_string() + &hash, serde_json::to_string(&TokenRecord::legacy()).unwrap()).unwrap();
+
+        assert!(cache.migrate(&mut connection, &hash).unwrap().is_none());
+        assert!(cache.get_token_for_user(hash.clone()).await.is_none());
+        assert!(connection.get::<_, Option<String>>("token.".to_string() + &hash).unwrap().is_some());
+
+        let _: usize = connection.del("token.".to_string() + &hash).unwrap();
+    }
+}
@@ -1,2 +1,3 @@
 pub mod cache;
-pub mod api;
\ No newline at end of file
+pub mod api;
+pub mod session;
\ No newline at end of file
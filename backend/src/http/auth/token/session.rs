@@ -0,0 +1,175 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rand::{distr::Alphanumeric, rng, Rng, RngCore};
+use redis::{Commands, ExistenceCheck, SetExpiry, SetOptions};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use sha2::Sha256;
+
+use crate::domain::Tokens::{Session, Tokens};
+
+/// The session key is derived from the token secret under this label.
+const KEY_LABEL: &[u8] = b"npm-proxy session encryption";
+
+
+/// The OIDC tokens of every login, encrypted in Redis under `session.<id>`.
+#[derive(Clone)]
+pub struct Sessions {
+    redis: redis::Client,
+    key: LessSafeKey
+}
+
+impl Sessions {
+
+    pub fn new(redis: redis::Client, secret: &str) -> Self {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(KEY_LABEL);
+        let key = UnboundKey::new(&AES_256_GCM, &mac.finalize().into_bytes()).unwrap();
+
+        return Self { redis, key: LessSafeKey::new(key) };
+    }
+
+    /// Keeps the tokens of a login, the id of its session.
+    pub fn start(&self, tokens: &Tokens) -> Result<String, redis::RedisError> {
+        let id: String = rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+
+        self.store(&id, &Session {
+            subject: tokens.identity.subject.clone(),
+            access_token: tokens.access_token.clone(),
+            refresh_token: tokens.refresh_token.clone(),
+            refreshed: Utc::now().timestamp()
+        })?;
+
+        return Ok(id);
+    }
+
+    pub fn store(&self, id: &str, session: &Session) -> Result<(), redis::RedisError> {
+        let mut connection = self.redis.get_connection()?;
+        return connection.set("session.".to_string() + id, self.seal(id, session));
+    }
+
+    pub fn end(&self, id: &str) -> Result<(), redis::RedisError> {
+        let mut connection = self.redis.get_connection()?;
+        let _: usize = connection.del("session.".to_string() + id)?;
+        return Ok(());
+    }
+
+    /// Sessions last refreshed at least `interval` ago. Ones that do not decrypt are dropped,
+    /// the secret changed and their tokens are gone with it.
+    pub fn due(&self, interval: Duration) -> Result<Vec<(String, Session)>, redis::RedisError> {
+        let mut connection = self.redis.get_connection()?;
+        let keys: Vec<String> = connection.scan_match::<_, String>("session.*")?.collect();
+        let now = Utc::now().timestamp();
+        let mut found = Vec::new();
+
+        for key in keys {
+            let id = key.strip_prefix("session.").unwrap();
+            let Some(stored) = connection.get::<_, Option<String>>(&key)? else {
+                continue;
+            };
+
+            match self.open(id, &stored) {
+                Some(session) if now - session.refreshed >= interval.as_secs() as i64 => found.push((id.to_string(), session)),
+                Some(_) => {},
+                None => {
+                    println!("Could not decrypt session {id}, dropping it");
+                    let _: usize = connection.del(&key)?;
+                }
+            }
+        }
+
+        return Ok(found);
+    }
+
+    /// Whether this replica gets to refresh the session, only one does within `interval`.
+    /// Refresh tokens are often single use, a second refresh would look like a refusal.
+    pub fn claim(&self, id: &str, interval: Duration) -> Result<bool, redis::RedisError> {
+        let mut connection = self.redis.get_connection()?;
+        let options = SetOptions::default().conditional_set(ExistenceCheck::NX).with_expiration(SetExpiry::EX(interval.as_secs()));
+        let claimed: Option<String> = connection.set_options("session-lock.".to_string() + id, 1, options)?;
+
+        return Ok(claimed.is_some());
+    }
+
+    /// A random nonce followed by the sealed JSON, bound to the id so a session cannot be
+    /// moved to another one.
+    fn seal(&self, id: &str, session: &Session) -> String {
+        let mut nonce = [0u8; NONCE_LEN];
+        rng().fill_bytes(&mut nonce);
+
+        let mut sealed = serde_json::to_vec(session).unwrap();
+        self.key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(id.as_bytes()), &mut sealed).unwrap();
+
+        return hex::encode(nonce) + &hex::encode(sealed);
+    }
+
+    fn open(&self, id: &str, stored: &str) -> Option<Session> {
+        let bytes = hex::decode(stored).ok().filter(|bytes| bytes.len() > NONCE_LEN)?;
+        let (nonce, sealed) = bytes.split_at(NONCE_LEN);
+        let mut sealed = sealed.to_vec();
+
+        let opened = self.key.open_in_place(Nonce::try_assume_unique_for_key(nonce).ok()?, Aad::from(id.as_bytes()), &mut sealed).ok()?;
+        return serde_json::from_slice(opened).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sessions(secret: &str) -> Sessions {
+        return Sessions::new(redis::Client::open("redis://127.0.0.1/").unwrap(), secret);
+    }
+
+    fn session() -> Session {
+        return Session {
+            subject: "alice".to_string(),
+            access_token: "access".to_string(),
+            refresh_token: "refresh".to_string(),
+            refreshed: 1700000000
+        };
+    }
+
+    #[test]
+    fn opens_sealed_sessions() {
+        let sessions = sessions("secret");
+        let sealed = sessions.seal("id", &session());
+        let opened = sessions.open("id", &sealed).unwrap();
+
+        assert!(!sealed.contains("refresh"));
+        assert_ne!(sealed, sessions.seal("id", &session()));
+        assert_eq!(opened.subject, "alice");
+        assert_eq!(opened.access_token, "access");
+        assert_eq!(opened.refresh_token, "refresh");
+        assert_eq!(opened.refreshed, 1700000000);
+    }
+
+    #[test]
+    fn refuses_tampered_sessions() {
+        let sessions = sessions("secret");
+        let sealed = sessions.seal("id", &session());
+
+        for position in [0, NONCE_LEN * 2, sealed.len() - 1] {
+            let mut tampered = sealed.clone().into_bytes();
+            tampered[position] = if tampered[position] == b'0' { b'1' } else { b'0' };
+            assert!(sessions.open("id", &String::from_utf8(tampered).unwrap()).is_none());
+        }
+
+        assert!(sessions.open("id", &sealed[..sealed.len() - 2]).is_none());
+        assert!(sessions.open("id", &sealed[..NONCE_LEN * 2]).is_none());
+        assert!(sessions.open("id", "not hex").is_none());
+    }
+
+    #[test]
+    fn refuses_moved_sessions() {
+        let sealed = sessions("secret").seal("id", &session());
+
+        assert!(sessions("secret").open("other", &sealed).is_none());
+        assert!(sessions("rotated").open("id", &sealed).is_none());
+    }
+}
@@ -1,9 +1,11 @@
+#![allow(clippy::needless_return)]
 
 mod http;
 mod config;
 mod domain;
 
 use std::collections::HashMap;
+use std::net::SocketAddr;
 
 use axum::extract::{Query, Request, State};
 use axum::middleware::Next;
@@ -26,7 +28,21 @@ async fn main() {
 
     dotenv::dotenv().ok();
 
-    let conf = config::Config::new();
+    let conf = match config::Config::new() {
+        Ok(conf) => conf,
+        Err(error) => {
+            println!("Could not start: {error}");
+            std::process::exit(1);
+        }
+    };
+
+    let routes = match api_routes(Router::new(), &conf) {
+        Ok(routes) => routes,
+        Err(error) => {
+            println!("Could not start: {error}");
+            std::process::exit(1);
+        }
+    };
 
     let redis = redis::Client::open(conf.redis_uri.clone()).unwrap();
 
@@ -42,14 +58,14 @@ async fn main() {
 
     let frontend = ServeDir::new("./public/");
 
-    let mut app = api.routes(api_routes(Router::new(), &conf))
+    let mut app = api.routes(routes)
         .route("/", get(|| async move {
             return Redirect::temporary("/ui/").into_response();
         }))
         .route_layer(middleware::from_fn_with_state((auth.clone(), api.clone()), |State((state, api)): State<(Authenticator, AuthenticatorApi)>, Query(params):Query<HashMap<String, String>>, jar: CookieJar, req: Request, next: Next| async move  {
             if req.uri().path().eq("/") && params.contains_key("code")  {
                 let token = state.get_from_redirected(params.get("code").unwrap().clone(), jar.get("_csrf").unwrap().to_string()).await;
-                if params.contains_key("state") {
+                if let Some(token) = token && params.contains_key("state") {
                     let _ = api.unlock(params.get("state").unwrap().to_string(), token).await;
                 }
             }
@@ -71,7 +87,7 @@ async fn main() {
 
         println!("Starting app on port: 5000");
     let listener = tokio::net::TcpListener::bind("0.0.0.0:5000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
 }
 
 